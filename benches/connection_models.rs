@@ -0,0 +1,240 @@
+/*
+ * Compares the async load balancer with the thread-per-connection model it
+ * replaced, both proxying to the same local backend:
+ *
+ *   cargo bench --bench connection_models > /dev/null
+ *
+ * The balancer logs every connection to stdout, so results go to stderr.
+ * BENCH_IDLE_CONNECTIONS sets how many idle clients each model has to hold
+ * (default 2000); each costs two file descriptors while held.
+ */
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use load_balancer::{balancer::LoadBalancer, config::Config};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const ASYNC_ADDRESS: &str = "127.0.0.1:19180";
+const THREADED_ADDRESS: &str = "127.0.0.1:19181";
+const BACKEND_ADDRESS: &str = "127.0.0.1:19182";
+
+const CONCURRENCY: usize = 64;
+const REQUESTS_PER_CLIENT: usize = 150;
+const PROBES: usize = 200;
+
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: bench\r\nConnection: close\r\n\r\n";
+const RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+
+fn main() {
+    let idle_connections = env::var("BENCH_IDLE_CONNECTIONS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(2000);
+
+    start_backend();
+    start_async_balancer();
+    start_threaded_proxy();
+    thread::sleep(Duration::from_millis(200));
+
+    for (model, address) in [("async", ASYNC_ADDRESS), ("threaded", THREADED_ADDRESS)] {
+        eprintln!("{} ({})", model, address);
+        throughput(address);
+        idle(address, idle_connections);
+        eprintln!();
+    }
+}
+
+/*
+ * `CONCURRENCY` clients sending `REQUESTS_PER_CLIENT` requests each, on a
+ * new connection every time since the threaded model cannot keep them alive
+ */
+fn throughput(address: &'static str) {
+    let latencies = Arc::new(Mutex::new(Vec::with_capacity(
+        CONCURRENCY * REQUESTS_PER_CLIENT,
+    )));
+    let started = Instant::now();
+    let clients: Vec<_> = (0..CONCURRENCY)
+        .map(|_| {
+            let latencies = Arc::clone(&latencies);
+            thread::spawn(move || {
+                let mut own = Vec::with_capacity(REQUESTS_PER_CLIENT);
+                for _ in 0..REQUESTS_PER_CLIENT {
+                    own.push(request(address));
+                }
+                latencies.lock().unwrap().extend(own);
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+    let elapsed = started.elapsed();
+
+    let mut latencies = latencies.lock().unwrap();
+    eprintln!(
+        "  {} requests from {} clients: {:.0} req/s, {}",
+        latencies.len(),
+        CONCURRENCY,
+        latencies.len() as f64 / elapsed.as_secs_f64(),
+        percentiles(&mut latencies)
+    );
+}
+
+/*
+ * Holds `count` clients that have sent half a request head, then measures
+ * what they cost in threads and how much they slow down other requests
+ */
+fn idle(address: &str, count: usize) {
+    let threads_before = thread_count();
+    let mut held = Vec::with_capacity(count);
+    let started = Instant::now();
+    for _ in 0..count {
+        match TcpStream::connect(address) {
+            Ok(mut stream) => {
+                let _ = stream.write_all(b"GET / HTTP/1.1\r\n");
+                held.push(stream);
+            }
+            Err(e) => {
+                eprintln!("  gave up opening idle connections: {}", e);
+                break;
+            }
+        }
+    }
+    let opened = started.elapsed();
+    thread::sleep(Duration::from_millis(500));
+    let threads_held = thread_count();
+
+    let mut latencies: Vec<_> = (0..PROBES).map(|_| request(address)).collect();
+    eprintln!(
+        "  {} idle connections opened in {:?}, {} extra threads, requests meanwhile {}",
+        held.len(),
+        opened,
+        match (threads_before, threads_held) {
+            (Some(before), Some(held)) => (held.saturating_sub(before)).to_string(),
+            _ => "n/a".to_string(),
+        },
+        percentiles(&mut latencies)
+    );
+
+    drop(held);
+    // Let both models notice the closed connections before the next round
+    thread::sleep(Duration::from_secs(1));
+}
+
+fn request(address: &str) -> Duration {
+    let started = Instant::now();
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(REQUEST).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert!(response.ends_with(b"ok"), "unexpected response");
+    started.elapsed()
+}
+
+fn percentiles(latencies: &mut [Duration]) -> String {
+    latencies.sort();
+    let at = |percent: usize| latencies[(latencies.len() - 1) * percent / 100];
+    format!("p50 {:?}, p99 {:?}", at(50), at(99))
+}
+
+fn thread_count() -> Option<usize> {
+    fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("Threads:"))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn start_backend() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let listener = runtime
+        .block_on(tokio::net::TcpListener::bind(BACKEND_ADDRESS))
+        .unwrap();
+    thread::spawn(move || {
+        runtime.block_on(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buffer = [0; 1024];
+                    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match stream.read(&mut buffer).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buffer[..n]),
+                        }
+                    }
+                    let _ = stream.write_all(RESPONSE).await;
+                });
+            }
+        })
+    });
+}
+
+fn start_async_balancer() {
+    let config = Config::parse(
+        Path::new("bench.toml"),
+        &format!(
+            r#"
+            [[listener]]
+            address = "{}"
+            pool = "bench"
+
+            [[pool.bench.backend]]
+            address = "{}"
+            "#,
+            ASYNC_ADDRESS, BACKEND_ADDRESS
+        ),
+    )
+    .unwrap();
+    let balancer = LoadBalancer::new(&config);
+    thread::spawn(move || balancer.start().unwrap());
+}
+
+/*
+ * The model the balancer used before: a thread per client plus one more for
+ * the client -> backend direction of the relay
+ */
+fn start_threaded_proxy() {
+    let listener = TcpListener::bind(THREADED_ADDRESS).unwrap();
+    thread::spawn(move || {
+        for client in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let _ = proxy(client);
+            });
+        }
+    });
+}
+
+fn proxy(mut client: TcpStream) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 4096];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = client.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..n]);
+    }
+
+    let mut backend = TcpStream::connect(BACKEND_ADDRESS)?;
+    backend.write_all(&request)?;
+
+    let (mut client_reader, mut backend_writer) = (client.try_clone()?, backend.try_clone()?);
+    let upstream = thread::spawn(move || {
+        let _ = io::copy(&mut client_reader, &mut backend_writer);
+        let _ = backend_writer.shutdown(Shutdown::Write);
+    });
+    let _ = io::copy(&mut backend, &mut client);
+    let _ = client.shutdown(Shutdown::Write);
+    let _ = upstream.join();
+    Ok(())
+}
@@ -0,0 +1,697 @@
+use std::{
+    fmt::{self, Write as _},
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender, SyncSender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    config::{AccessLogConfig, LogFormat},
+    http::RequestHead,
+    relay::TimeoutCause,
+};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/*
+ * How a request, or a passthrough connection, came to an end
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Termination {
+    /*
+     * The whole response reached the client
+     */
+    Completed,
+    /*
+     * Handed over to the raw relay after a protocol switch or CONNECT
+     */
+    Upgraded,
+    /*
+     * A passthrough connection ran its course
+     */
+    Relayed,
+    BadRequest,
+    HeadTooLarge,
+    ClientTimeout,
+    NoRoute,
+    NoBackend,
+    BackendFailed,
+    BackendTimeout,
+    /*
+     * The client went away or stopped reading mid-response
+     */
+    ClientAborted,
+    /*
+     * The backend broke off its response after it had started
+     */
+    BackendAborted,
+    /*
+     * Neither side sent anything for the idle timeout
+     */
+    IdleTimeout,
+    DeadlineExceeded,
+}
+
+impl Termination {
+    /*
+     * The reason behind one of the balancer's own error responses
+     */
+    pub fn for_status(status: u16) -> Self {
+        match status {
+            404 => Termination::NoRoute,
+            408 => Termination::ClientTimeout,
+            431 => Termination::HeadTooLarge,
+            502 => Termination::BackendFailed,
+            503 => Termination::NoBackend,
+            504 => Termination::BackendTimeout,
+            _ => Termination::BadRequest,
+        }
+    }
+
+    /*
+     * The reason behind a relay that one of the limits cut short
+     */
+    pub fn timed_out(cause: TimeoutCause) -> Self {
+        match cause {
+            TimeoutCause::Connect | TimeoutCause::FirstByte => Termination::BackendTimeout,
+            TimeoutCause::Idle => Termination::IdleTimeout,
+            TimeoutCause::Deadline => Termination::DeadlineExceeded,
+        }
+    }
+}
+
+impl fmt::Display for Termination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Termination::Completed => "completed",
+            Termination::Upgraded => "upgraded",
+            Termination::Relayed => "relayed",
+            Termination::BadRequest => "bad_request",
+            Termination::HeadTooLarge => "head_too_large",
+            Termination::ClientTimeout => "client_timeout",
+            Termination::NoRoute => "no_route",
+            Termination::NoBackend => "no_backend",
+            Termination::BackendFailed => "backend_failed",
+            Termination::BackendTimeout => "backend_timeout",
+            Termination::ClientAborted => "client_aborted",
+            Termination::BackendAborted => "backend_aborted",
+            Termination::IdleTimeout => "idle_timeout",
+            Termination::DeadlineExceeded => "deadline_exceeded",
+        })
+    }
+}
+
+/*
+ * What is known about one request, filled in as it goes along and written
+ * out once it is done
+ */
+#[derive(Debug)]
+pub struct Entry {
+    time: SystemTime,
+    started: Instant,
+    client: SocketAddr,
+    method: Option<String>,
+    path: Option<String>,
+    version: Option<u8>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    /*
+     * SNI of a passthrough connection
+     */
+    pub server_name: Option<String>,
+    pub status: Option<u16>,
+    /*
+     * Sent to the client, response head included
+     */
+    pub bytes: u64,
+    pub pool: Option<String>,
+    /*
+     * The last backend tried
+     */
+    pub backend: Option<String>,
+    pub attempts: u32,
+    /*
+     * Time to connect to the backend; unset for a reused connection
+     */
+    pub connect_time: Option<Duration>,
+    /*
+     * Time from the start of the last attempt until the backend's response
+     * head arrived
+     */
+    pub response_time: Option<Duration>,
+    pub termination: Option<Termination>,
+}
+
+impl Entry {
+    pub fn new(client: SocketAddr) -> Self {
+        Entry {
+            time: SystemTime::now(),
+            started: Instant::now(),
+            client,
+            method: None,
+            path: None,
+            version: None,
+            referer: None,
+            user_agent: None,
+            server_name: None,
+            status: None,
+            bytes: 0,
+            pool: None,
+            backend: None,
+            attempts: 0,
+            connect_time: None,
+            response_time: None,
+            termination: None,
+        }
+    }
+
+    /*
+     * Marks the arrival of the request head, which is the time logged. The
+     * wait for it on a keep-alive connection does not count.
+     */
+    pub fn start(&mut self) {
+        self.time = SystemTime::now();
+        self.started = Instant::now();
+    }
+
+    pub fn request(&mut self, request: &RequestHead) {
+        self.method = Some(request.method.clone());
+        self.path = Some(request.path.clone());
+        self.version = Some(request.version);
+        self.referer = request.header("referer").map(str::to_string);
+        self.user_agent = request.header("user-agent").map(str::to_string);
+    }
+
+    /*
+     * One of the balancer's own responses, given as sent
+     */
+    pub fn responded(&mut self, response: &str) {
+        let status = response.get(9..12).and_then(|status| status.parse().ok());
+        self.status = status;
+        self.bytes = response.len() as u64;
+        if let Some(status) = status {
+            self.termination
+                .get_or_insert(Termination::for_status(status));
+        }
+    }
+
+    fn combined(&self, duration: Duration) -> String {
+        let mut line = format!("{} - - [{}] ", self.client.ip(), clf_time(self.time));
+        match (&self.method, &self.path) {
+            (Some(method), Some(path)) => {
+                let request = format!("{} {} HTTP/1.{}", method, path, self.version.unwrap_or(1));
+                quote(&mut line, Some(&request));
+            }
+            _ => quote(&mut line, None),
+        }
+        let status = self
+            .status
+            .map_or("-".to_string(), |status| status.to_string());
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+        let _ = write!(line, " {} {} ", status, bytes);
+        quote(&mut line, self.referer.as_deref());
+        line.push(' ');
+        quote(&mut line, self.user_agent.as_deref());
+
+        let fields = [
+            ("sni", self.server_name.clone()),
+            ("pool", self.pool.clone()),
+            ("backend", self.backend.clone()),
+            ("attempts", Some(self.attempts.to_string())),
+            ("connect", self.connect_time.map(seconds)),
+            ("response", self.response_time.map(seconds)),
+            ("duration", Some(seconds(duration))),
+            (
+                "termination",
+                self.termination.map(|termination| termination.to_string()),
+            ),
+        ];
+        for (key, value) in fields {
+            if let Some(value) = value {
+                // Pool names are free-form; keep each field one token
+                let value: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+                let _ = write!(line, " {}={}", key, value);
+            }
+        }
+        line
+    }
+
+    fn json(&self, duration: Duration) -> String {
+        let text = |value: Option<&str>| value.map_or("null".to_string(), json_string);
+        let number = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+        let protocol = self.version.map(|version| format!("HTTP/1.{}", version));
+        let fields = [
+            ("time", json_string(&rfc3339(self.time))),
+            ("client", json_string(&self.client.ip().to_string())),
+            ("client_port", self.client.port().to_string()),
+            ("method", text(self.method.as_deref())),
+            ("path", text(self.path.as_deref())),
+            ("protocol", text(protocol.as_deref())),
+            ("server_name", text(self.server_name.as_deref())),
+            (
+                "status",
+                number(self.status.map(|status| status.to_string())),
+            ),
+            ("bytes", self.bytes.to_string()),
+            ("referer", text(self.referer.as_deref())),
+            ("user_agent", text(self.user_agent.as_deref())),
+            ("pool", text(self.pool.as_deref())),
+            ("backend", text(self.backend.as_deref())),
+            ("attempts", self.attempts.to_string()),
+            ("connect_time", number(self.connect_time.map(seconds))),
+            ("response_time", number(self.response_time.map(seconds))),
+            ("duration", seconds(duration)),
+            (
+                "termination",
+                text(self.termination.map(|t| t.to_string()).as_deref()),
+            ),
+        ];
+        let mut line = String::from("{");
+        for (index, (key, value)) in fields.iter().enumerate() {
+            if index > 0 {
+                line.push(',');
+            }
+            let _ = write!(line, "\"{}\":{}", key, value);
+        }
+        line.push('}');
+        line
+    }
+}
+
+/*
+ * Most lines queued for the writer before further ones are dropped, so a
+ * stalled disk costs log lines rather than memory
+ */
+const BACKLOG: usize = 64 * 1024;
+
+/*
+ * Where entries go. Requests only format their line and queue it; a thread
+ * of its own writes the lines out in order, so a slow disk or a blocked
+ * standard output never holds up a runtime worker.
+ */
+#[derive(Debug)]
+pub struct AccessLog {
+    queue: SyncSender<Message>,
+    json: AtomicBool,
+    /*
+     * Lines lost to a full queue since the writer last reported them
+     */
+    dropped: Arc<AtomicU64>,
+}
+
+#[derive(Debug)]
+enum Message {
+    Line(String),
+    Reopen(Sender<io::Result<()>>),
+    Configure(Option<PathBuf>, Sender<io::Result<()>>),
+    /*
+     * Answered once every line queued before it is written
+     */
+    Flush(Sender<io::Result<()>>),
+}
+
+/*
+ * The writer thread's end: the destination and the file open on it
+ */
+#[derive(Debug)]
+struct Output {
+    path: Option<PathBuf>,
+    /*
+     * Opened on first use, and again after a reopen
+     */
+    file: Option<File>,
+}
+
+impl AccessLog {
+    pub fn new(config: &AccessLogConfig) -> Self {
+        let (queue, messages) = mpsc::sync_channel(BACKLOG);
+        let output = Output {
+            path: config.path.clone(),
+            file: None,
+        };
+        let dropped = Arc::new(AtomicU64::new(0));
+        {
+            let dropped = Arc::clone(&dropped);
+            thread::spawn(move || output.run(messages, &dropped));
+        }
+        AccessLog {
+            queue,
+            json: AtomicBool::new(config.format == LogFormat::Json),
+            dropped,
+        }
+    }
+
+    pub fn write(&self, entry: &Entry) {
+        let duration = entry.started.elapsed();
+        let mut line = if self.json.load(Ordering::Relaxed) {
+            entry.json(duration)
+        } else {
+            entry.combined(duration)
+        };
+        line.push('\n');
+        if self.queue.try_send(Message::Line(line)).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /*
+     * Opens the log file afresh, for after it was rotated away. The old file
+     * stays in use if the new one cannot be opened.
+     */
+    pub fn reopen(&self) -> io::Result<()> {
+        self.ask(Message::Reopen)
+    }
+
+    /*
+     * Switches to the format and destination of a reloaded config
+     */
+    pub fn configure(&self, config: &AccessLogConfig) -> io::Result<()> {
+        let path = config.path.clone();
+        self.ask(|reply| Message::Configure(path, reply))?;
+        self.json
+            .store(config.format == LogFormat::Json, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /*
+     * Waits until the lines logged so far are written
+     */
+    pub fn flush(&self) -> io::Result<()> {
+        self.ask(Message::Flush)
+    }
+
+    /*
+     * Queues a message behind the lines already waiting and blocks for the
+     * writer's answer
+     */
+    fn ask(&self, message: impl FnOnce(Sender<io::Result<()>>) -> Message) -> io::Result<()> {
+        let stopped = || io::Error::other("the access log writer has stopped");
+        let (reply, answer) = mpsc::channel();
+        self.queue.send(message(reply)).map_err(|_| stopped())?;
+        answer.recv().map_err(|_| stopped())?
+    }
+}
+
+impl Output {
+    /*
+     * Handles messages until the AccessLog is dropped
+     */
+    fn run(mut self, messages: Receiver<Message>, dropped: &AtomicU64) {
+        for message in messages {
+            match message {
+                Message::Line(line) => self.write(&line),
+                Message::Reopen(reply) => {
+                    let _ = reply.send(self.reopen());
+                }
+                Message::Configure(path, reply) => {
+                    let _ = reply.send(self.configure(path));
+                }
+                Message::Flush(reply) => {
+                    let _ = reply.send(Ok(()));
+                }
+            }
+            match dropped.swap(0, Ordering::Relaxed) {
+                0 => {}
+                lost => eprintln!("Access log fell behind, dropped {} lines", lost),
+            }
+        }
+    }
+
+    fn write(&mut self, line: &str) {
+        let written = match &self.path {
+            None => io::stdout().lock().write_all(line.as_bytes()),
+            Some(path) => match &mut self.file {
+                Some(file) => file.write_all(line.as_bytes()),
+                None => open(path).and_then(|mut file| {
+                    let written = file.write_all(line.as_bytes());
+                    self.file = Some(file);
+                    written
+                }),
+            },
+        };
+        if let Err(e) = written {
+            eprintln!("Writing access log failed: {}", e);
+        }
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            self.file = Some(open(path)?);
+        }
+        Ok(())
+    }
+
+    fn configure(&mut self, path: Option<PathBuf>) -> io::Result<()> {
+        if self.path != path {
+            self.file = match &path {
+                Some(path) => Some(open(path)?),
+                None => None,
+            };
+        }
+        self.path = path;
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+/*
+ * A quoted field as Apache writes it: `-` when absent, with quotes,
+ * backslashes and control characters escaped
+ */
+fn quote(line: &mut String, value: Option<&str>) {
+    let Some(value) = value else {
+        line.push_str("\"-\"");
+        return;
+    };
+    line.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(line, "\\x{:02x}", c as u32);
+            }
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+/*
+ * A JSON string literal, also used by the admin API
+ */
+pub fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{:.6}", duration.as_secs_f64())
+}
+
+/*
+ * `10/Oct/2026:13:55:36 +0000`; the log is always in UTC
+ */
+fn clf_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second, _) = utc(time);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+/*
+ * `2026-10-10T13:55:36.123Z`
+ */
+pub fn rfc3339(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second, millis) = utc(time);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, hour, minute, second, millis
+    )
+}
+
+/*
+ * Splits a time into its UTC calendar fields, down to milliseconds. The
+ * date part follows Howard Hinnant's `civil_from_days`.
+ */
+fn utc(time: SystemTime) -> (i64, u32, u32, u32, u32, u32, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs() as i64;
+    let (days, of_day) = (
+        seconds.div_euclid(86_400),
+        seconds.rem_euclid(86_400) as u32,
+    );
+
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (
+        year,
+        month,
+        day,
+        of_day / 3600,
+        of_day / 60 % 60,
+        of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: u64, millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(seconds * 1000 + millis)
+    }
+
+    /*
+     * A request with every field set, logged at the leap day of 2024
+     */
+    fn entry() -> Entry {
+        let raw = "GET /search?q=\"x\" HTTP/1.1\r\n\
+                   Host: example.com\r\n\
+                   Referer: https://example.com/\r\n\
+                   User-Agent: curl/8.5.0\r\n\r\n";
+        let mut entry = Entry::new("192.0.2.7:51234".parse().unwrap());
+        entry.time = at(1_709_210_096, 42);
+        entry.request(&RequestHead::parse(raw.as_bytes()).unwrap());
+        entry.status = Some(200);
+        entry.bytes = 1234;
+        entry.pool = Some("web pool".to_string());
+        entry.backend = Some("10.0.0.1:80".to_string());
+        entry.attempts = 2;
+        entry.connect_time = Some(Duration::from_micros(1500));
+        entry.response_time = Some(Duration::from_millis(20));
+        entry.termination = Some(Termination::Completed);
+        entry
+    }
+
+    #[test]
+    fn splits_times_into_utc_fields() {
+        assert_eq!(utc(UNIX_EPOCH), (1970, 1, 1, 0, 0, 0, 0));
+        assert_eq!(utc(at(1_709_210_096, 42)), (2024, 2, 29, 12, 34, 56, 42));
+        assert_eq!(utc(at(951_868_800, 0)), (2000, 3, 1, 0, 0, 0, 0));
+        assert_eq!(utc(at(951_868_799, 999)), (2000, 2, 29, 23, 59, 59, 999));
+        // 2100 is no leap year
+        assert_eq!(utc(at(4_107_542_400, 0)), (2100, 3, 1, 0, 0, 0, 0));
+        assert_eq!(utc(at(4_107_542_399, 0)), (2100, 2, 28, 23, 59, 59, 0));
+    }
+
+    #[test]
+    fn formats_times() {
+        assert_eq!(clf_time(UNIX_EPOCH), "01/Jan/1970:00:00:00 +0000");
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            clf_time(at(1_709_210_096, 42)),
+            "29/Feb/2024:12:34:56 +0000"
+        );
+        assert_eq!(rfc3339(at(1_709_210_096, 42)), "2024-02-29T12:34:56.042Z");
+        assert_eq!(rfc3339(at(4_107_542_400, 0)), "2100-03-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string(""), r#""""#);
+        assert_eq!(json_string("caf\u{e9}"), "\"caf\u{e9}\"");
+        assert_eq!(json_string(r#"a "b" \c"#), r#""a \"b\" \\c""#);
+        assert_eq!(json_string("\n\r\t"), r#""\n\r\t""#);
+        assert_eq!(json_string("\0\x1b\x7f"), r#""\u0000\u001b\u007f""#);
+    }
+
+    #[test]
+    fn writes_a_combined_line() {
+        assert_eq!(
+            entry().combined(Duration::from_millis(25)),
+            concat!(
+                r#"192.0.2.7 - - [29/Feb/2024:12:34:56 +0000] "#,
+                r#""GET /search?q=\"x\" HTTP/1.1" 200 1234 "#,
+                r#""https://example.com/" "curl/8.5.0" pool=webpool "#,
+                r#"backend=10.0.0.1:80 attempts=2 connect=0.001500 "#,
+                r#"response=0.020000 duration=0.025000 termination=completed"#,
+            )
+        );
+    }
+
+    #[test]
+    fn writes_a_combined_line_for_a_bare_connection() {
+        let mut entry = Entry::new("[2001:db8::1]:443".parse().unwrap());
+        entry.time = UNIX_EPOCH;
+        entry.server_name = Some("example.com".to_string());
+        entry.termination = Some(Termination::Relayed);
+        assert_eq!(
+            entry.combined(Duration::ZERO),
+            "2001:db8::1 - - [01/Jan/1970:00:00:00 +0000] \"-\" - - \"-\" \"-\" \
+             sni=example.com attempts=0 duration=0.000000 termination=relayed"
+        );
+    }
+
+    #[test]
+    fn writes_a_json_line() {
+        assert_eq!(
+            entry().json(Duration::from_millis(25)),
+            concat!(
+                r#"{"time":"2024-02-29T12:34:56.042Z","client":"192.0.2.7","#,
+                r#""client_port":51234,"method":"GET","path":"/search?q=\"x\"","#,
+                r#""protocol":"HTTP/1.1","server_name":null,"status":200,"#,
+                r#""bytes":1234,"referer":"https://example.com/","#,
+                r#""user_agent":"curl/8.5.0","pool":"web pool","#,
+                r#""backend":"10.0.0.1:80","attempts":2,"connect_time":0.001500,"#,
+                r#""response_time":0.020000,"duration":0.025000,"#,
+                r#""termination":"completed"}"#,
+            )
+        );
+    }
+}
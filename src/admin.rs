@@ -0,0 +1,500 @@
+use std::{fmt::Write, io, time::Duration};
+
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+use crate::{
+    access::{json_string, rfc3339},
+    backend::{Backend, Pool},
+    balancer::LoadBalancer,
+    config::Address,
+    http::{HeadState, RequestHead},
+    relay::{read_head, Limits, Stop, TimeoutCause},
+};
+
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+const OK: &str = "200 OK";
+const CREATED: &str = "201 Created";
+const BAD_REQUEST: &str = "400 Bad Request";
+const UNAUTHORIZED: &str = "401 Unauthorized";
+const NOT_FOUND: &str = "404 Not Found";
+const METHOD_NOT_ALLOWED: &str = "405 Method Not Allowed";
+const CONFLICT: &str = "409 Conflict";
+
+/*
+ * Status line and JSON body of one answer
+ */
+struct Reply {
+    status: &'static str,
+    body: String,
+    /*
+     * The methods a 405 names in its Allow header
+     */
+    allow: Option<&'static str>,
+}
+
+impl Reply {
+    fn new(status: &'static str, body: String) -> Self {
+        Reply {
+            status,
+            body,
+            allow: None,
+        }
+    }
+
+    fn error(status: &'static str, message: &str) -> Self {
+        Reply::new(status, format!("{{\"error\":{}}}", json_string(message)))
+    }
+}
+
+/*
+ * Accepts admin connections until the process exits, one request each
+ */
+pub async fn serve(balancer: LoadBalancer, listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let balancer = balancer.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle(&balancer, stream).await {
+                        eprintln!("Error serving the admin API: {}", e)
+                    }
+                });
+            }
+            Err(e) => {
+                eprintln!("Error accepting connection {}", e);
+                time::sleep(ACCEPT_BACKOFF).await;
+            }
+        }
+    }
+}
+
+async fn handle(balancer: &LoadBalancer, stream: TcpStream) -> io::Result<()> {
+    let config = balancer.config();
+    let timeouts = &config.timeouts;
+    let mut limits = Limits {
+        idle: timeouts.idle,
+        first_byte: timeouts.first_byte,
+        deadline: None,
+    };
+    let mut reader = BufReader::new(stream);
+    let mut head = Vec::new();
+    let state = read_head(
+        &mut reader,
+        &mut head,
+        RequestHead::state,
+        &mut limits,
+        (timeouts.idle, TimeoutCause::Idle),
+        timeouts.request,
+    )
+    .await
+    .map_err(|stop| match stop {
+        Stop::Timeout(cause) => io::Error::new(io::ErrorKind::TimedOut, cause.to_string()),
+        Stop::Io(e) => e,
+    })?;
+    let request = match state {
+        HeadState::Complete => RequestHead::parse(&head),
+        _ => None,
+    };
+    let reply = match request {
+        Some(request) => answer(balancer, &request),
+        None => Reply::error(BAD_REQUEST, "malformed request"),
+    };
+
+    let challenge = if reply.status == UNAUTHORIZED {
+        "WWW-Authenticate: Bearer\r\n".to_string()
+    } else if let Some(allow) = reply.allow {
+        format!("Allow: {}\r\n", allow)
+    } else {
+        String::new()
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        reply.status,
+        challenge,
+        reply.body.len() + 1,
+        reply.body
+    );
+    let stream = reader.get_mut();
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/*
+ * Routes one authorized request:
+ *
+ *   GET    /pools                                   every pool and backend
+ *   GET    /pools/{pool}                            one pool
+ *   POST   /pools/{pool}/backends?address=&weight=  adds a backend
+ *   DELETE /pools/{pool}/backends/{address}         removes it
+ *   PUT    /pools/{pool}/backends/{address}/weight?value=
+ *   PUT    /pools/{pool}/backends/{address}/drain   no new connections
+ *   DELETE /pools/{pool}/backends/{address}/drain   back into rotation
+ */
+fn answer(balancer: &LoadBalancer, request: &RequestHead) -> Reply {
+    let config = balancer.config();
+    let token = request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+    let authorized = match (&config.admin, token) {
+        (Some(admin), Some(token)) => same(token.as_bytes(), admin.token.get_ref().as_bytes()),
+        _ => false,
+    };
+    if !authorized {
+        return Reply::error(UNAUTHORIZED, "missing or wrong bearer token");
+    }
+
+    let (path, query) = request
+        .path
+        .split_once('?')
+        .unwrap_or((request.path.as_str(), ""));
+    let segments: Vec<String> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(decode)
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    let pool = |name: &str| {
+        balancer
+            .pool(name)
+            .ok_or_else(|| Reply::error(NOT_FOUND, &format!("no pool `{}`", name)))
+    };
+    let backend = |pool: &Pool, address: &str| {
+        pool.backend(address).ok_or_else(|| {
+            Reply::error(
+                NOT_FOUND,
+                &format!("no backend `{}` in pool `{}`", address, pool.name()),
+            )
+        })
+    };
+
+    let reply = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["pools"]) => {
+            let pools = balancer.pools();
+            let mut pools: Vec<_> = pools.values().collect();
+            pools.sort_by(|a, b| a.name().cmp(b.name()));
+            let pools: Vec<String> = pools.iter().map(|pool| pool_json(pool)).collect();
+            Ok(Reply::new(
+                OK,
+                format!("{{\"pools\":[{}]}}", pools.join(",")),
+            ))
+        }
+        ("GET", ["pools", name]) => pool(name).map(|pool| Reply::new(OK, pool_json(&pool))),
+        ("POST", ["pools", name, "backends"]) => pool(name).and_then(|pool| {
+            let address = param(query, "address")
+                .ok_or_else(|| Reply::error(BAD_REQUEST, "the address parameter is required"))?
+                .parse::<Address>()
+                .map_err(|e| Reply::error(BAD_REQUEST, &e))?;
+            let weight = match param(query, "weight") {
+                Some(weight) => parse_weight(&weight)?,
+                None => 1,
+            };
+            if !pool.add_backend(Backend::new(address.to_string(), weight)) {
+                return Err(Reply::error(
+                    CONFLICT,
+                    &format!("pool `{}` already has backend `{}`", pool.name(), address),
+                ));
+            }
+            let added = backend(&pool, address.as_str())?;
+            Ok(Reply::new(CREATED, backend_json(&added)))
+        }),
+        ("DELETE", ["pools", name, "backends", address]) => pool(name).and_then(|pool| {
+            let removed = pool.remove_backend(address).ok_or_else(|| {
+                Reply::error(
+                    NOT_FOUND,
+                    &format!("no backend `{}` in pool `{}`", address, pool.name()),
+                )
+            })?;
+            Ok(Reply::new(OK, backend_json(&removed)))
+        }),
+        ("PUT", ["pools", name, "backends", address, "weight"]) => pool(name).and_then(|pool| {
+            let backend = backend(&pool, address)?;
+            let weight = param(query, "value")
+                .ok_or_else(|| Reply::error(BAD_REQUEST, "the value parameter is required"))?;
+            let weight = parse_weight(&weight)?;
+            eprintln!(
+                "Changing weight of backend {} in pool {} from {} to {}",
+                address,
+                pool.name(),
+                backend.weight(),
+                weight
+            );
+            backend.set_weight(weight);
+            Ok(Reply::new(OK, backend_json(&backend)))
+        }),
+        (method @ ("PUT" | "DELETE"), ["pools", name, "backends", address, "drain"]) => pool(name)
+            .and_then(|pool| {
+                let backend = backend(&pool, address)?;
+                let draining = method == "PUT";
+                if draining != backend.is_draining() {
+                    eprintln!(
+                        "{} backend {} in pool {} ({} active connections)",
+                        if draining { "Draining" } else { "Undraining" },
+                        address,
+                        pool.name(),
+                        backend.active_connections()
+                    );
+                    backend.set_draining(draining);
+                }
+                Ok(Reply::new(OK, backend_json(&backend)))
+            }),
+        (method, segments) => Err(match allowed(segments) {
+            Some(allow) => Reply {
+                allow: Some(allow),
+                ..Reply::error(
+                    METHOD_NOT_ALLOWED,
+                    &format!("{} is not allowed here, only {}", method, allow),
+                )
+            },
+            None => Reply::error(NOT_FOUND, "no such endpoint"),
+        }),
+    };
+    reply.unwrap_or_else(|error| error)
+}
+
+/*
+ * The methods `answer` takes for a path, or None for a path it does not know
+ */
+fn allowed(segments: &[&str]) -> Option<&'static str> {
+    match segments {
+        ["pools"] | ["pools", _] => Some("GET"),
+        ["pools", _, "backends"] => Some("POST"),
+        ["pools", _, "backends", _] => Some("DELETE"),
+        ["pools", _, "backends", _, "weight"] => Some("PUT"),
+        ["pools", _, "backends", _, "drain"] => Some("PUT, DELETE"),
+        _ => None,
+    }
+}
+
+fn parse_weight(value: &str) -> Result<u32, Reply> {
+    match value.parse::<u32>() {
+        Ok(weight) if weight >= 1 => Ok(weight),
+        _ => Err(Reply::error(
+            BAD_REQUEST,
+            &format!("weight `{}` must be a whole number of at least 1", value),
+        )),
+    }
+}
+
+fn pool_json(pool: &Pool) -> String {
+    let backends: Vec<String> = pool
+        .backends()
+        .iter()
+        .map(|backend| backend_json(backend))
+        .collect();
+    format!(
+        "{{\"name\":{},\"backends\":[{}]}}",
+        json_string(pool.name()),
+        backends.join(",")
+    )
+}
+
+fn backend_json(backend: &Backend) -> String {
+    let stats = backend.stats();
+    let check = backend.last_check();
+    let mut json = format!(
+        "{{\"address\":{},\"weight\":{},\"healthy\":{},\"ejected\":{},\"draining\":{},\"active_connections\":{},\"idle_connections\":{},\"requests\":{},\"errors\":{},\"last_check\":",
+        json_string(backend.address()),
+        backend.weight(),
+        backend.is_healthy(),
+        backend.is_ejected(),
+        backend.is_draining(),
+        backend.active_connections(),
+        backend.connections().idle_count(),
+        stats.requests(),
+        stats.errors()
+    );
+    match check.checked_at {
+        Some(time) => {
+            let _ = write!(
+                json,
+                "{{\"time\":\"{}\",\"passed\":{}}}",
+                rfc3339(time),
+                check.passed
+            );
+        }
+        None => json.push_str("null"),
+    }
+    json.push_str(",\"last_failure\":");
+    match &check.last_failure {
+        Some((time, reason)) => {
+            let _ = write!(
+                json,
+                "{{\"time\":\"{}\",\"reason\":{}}}",
+                rfc3339(*time),
+                json_string(reason)
+            );
+        }
+        None => json.push_str("null"),
+    }
+    json.push('}');
+    json
+}
+
+/*
+ * The decoded value of the first `name=value` pair in a query string
+ */
+fn param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| decode(value))
+}
+
+/*
+ * Undoes percent-encoding, which IPv6 backend addresses need in a path
+ */
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/*
+ * Compares the whole token whatever the first difference, so response
+ * times tell nothing about how much of a guess was right
+ */
+fn same(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::config::Config;
+
+    fn balancer() -> LoadBalancer {
+        let source = r#"
+            [admin]
+            token = "secret"
+
+            [[listener]]
+            address = "127.0.0.1:8080"
+            pool = "web"
+
+            [[pool.web.backend]]
+            address = "127.0.0.1:8081"
+        "#;
+        LoadBalancer::new(&Config::parse(Path::new("test.toml"), source).unwrap())
+    }
+
+    fn ask(balancer: &LoadBalancer, method: &str, path: &str, token: Option<&str>) -> Reply {
+        let authorization = token.map_or(String::new(), |token| {
+            format!("Authorization: Bearer {}\r\n", token)
+        });
+        let raw = format!("{method} {path} HTTP/1.1\r\nHost: admin\r\n{authorization}\r\n");
+        answer(balancer, &RequestHead::parse(raw.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(decode("%5B%3A%3A1%5D%3A80"), "[::1]:80");
+        assert_eq!(decode("caf%C3%A9"), "caf\u{e9}");
+        assert_eq!(decode("caf\u{e9}"), "caf\u{e9}");
+        assert_eq!(decode("%zz%4"), "%zz%4");
+        assert_eq!(decode("100%"), "100%");
+        // A lone continuation byte is not UTF-8
+        assert_eq!(decode("%80"), "\u{fffd}");
+    }
+
+    #[test]
+    fn compares_tokens_whole() {
+        assert!(same(b"secret", b"secret"));
+        assert!(!same(b"secreT", b"secret"));
+        assert!(!same(b"secret", b"secrets"));
+        assert!(!same(b"", b"secret"));
+        assert!(same(b"", b""));
+    }
+
+    #[test]
+    fn refuses_missing_or_wrong_tokens() {
+        let balancer = balancer();
+        for token in [None, Some("wrong"), Some("secrets")] {
+            let reply = ask(&balancer, "GET", "/pools", token);
+            assert_eq!(reply.status, UNAUTHORIZED, "{:?}", token);
+        }
+        // Even a path that does not exist gives nothing away
+        let reply = ask(&balancer, "GET", "/nowhere", None);
+        assert_eq!(reply.status, UNAUTHORIZED);
+    }
+
+    #[test]
+    fn dispatches_by_method_and_path() {
+        let balancer = balancer();
+        let ask = |method, path| ask(&balancer, method, path, Some("secret"));
+
+        let reply = ask("GET", "/pools");
+        assert_eq!(reply.status, OK);
+        assert!(reply.body.contains(r#""name":"web""#), "{}", reply.body);
+        assert_eq!(ask("GET", "/pools/web").status, OK);
+        assert_eq!(ask("GET", "/pools/api").status, NOT_FOUND);
+
+        let added = ask("POST", "/pools/web/backends?address=%5B%3A%3A1%5D%3A8082");
+        assert_eq!(added.status, CREATED);
+        assert!(
+            added.body.contains(r#""address":"[::1]:8082""#),
+            "{}",
+            added.body
+        );
+        let drained = ask("PUT", "/pools/web/backends/%5B%3A%3A1%5D%3A8082/drain");
+        assert!(
+            drained.body.contains(r#""draining":true"#),
+            "{}",
+            drained.body
+        );
+        assert_eq!(
+            ask("DELETE", "/pools/web/backends/%5B%3A%3A1%5D%3A8082").status,
+            OK
+        );
+
+        assert_eq!(ask("GET", "/").status, NOT_FOUND);
+        assert_eq!(
+            ask("GET", "/pools/web/backends/127.0.0.1:8081/health").status,
+            NOT_FOUND
+        );
+        for (method, path, allow) in [
+            ("DELETE", "/pools", "GET"),
+            ("GET", "/pools/web/backends", "POST"),
+            (
+                "POST",
+                "/pools/web/backends/127.0.0.1:8081/drain",
+                "PUT, DELETE",
+            ),
+        ] {
+            let reply = ask(method, path);
+            assert_eq!(reply.status, METHOD_NOT_ALLOWED, "{} {}", method, path);
+            assert_eq!(reply.allow, Some(allow));
+        }
+    }
+}
@@ -0,0 +1,727 @@
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock, RwLock,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use arc_swap::ArcSwap;
+
+use crate::{
+    config::{
+        Algorithm, BackendConfig, ConnectionPoolConfig, HealthCheckConfig, HttpCheckConfig,
+        OutlierDetectionConfig, PoolConfig, ProxyVersion, RetryConfig, UpstreamTlsConfig,
+    },
+    metrics::BackendStats,
+    outlier::{Observation, OutlierState},
+    retry::RetryBudget,
+    strategy::{self, BalancingStrategy, Outcome, RequestContext},
+    upstream::ConnectionPool,
+};
+
+#[derive(Debug)]
+pub struct Backend {
+    address: String,
+    weight: AtomicU32,
+    /*
+     * Running score of smooth weighted round robin. It lives with the
+     * backend so that it goes when the backend leaves the pool and stays
+     * while the backend is merely skipped.
+     */
+    score: AtomicI64,
+    active_connections: AtomicUsize,
+    healthy: AtomicBool,
+    /*
+     * Set through the admin API: no new connections, while the ones already
+     * open finish
+     */
+    draining: AtomicBool,
+    /*
+     * Consecutive health check results, reset whenever the result flips
+     */
+    passes: AtomicU32,
+    failures: AtomicU32,
+    last_check: Mutex<CheckRecord>,
+    http_check: RwLock<Option<HttpCheckConfig>>,
+    outlier: Mutex<OutlierState>,
+    /*
+     * When the current ejection ends, as `ticks`, or 0. Kept apart from
+     * `outlier` so that picks read it without locking.
+     */
+    ejected_until: AtomicU64,
+    connections: ConnectionPool,
+    stats: BackendStats,
+}
+
+/*
+ * Outcome of the most recent health checks of a backend
+ */
+#[derive(Clone, Debug, Default)]
+pub struct CheckRecord {
+    pub checked_at: Option<SystemTime>,
+    pub passed: bool,
+    /*
+     * Kept after the backend recovers, to explain the last outage
+     */
+    pub last_failure: Option<(SystemTime, String)>,
+}
+
+impl Backend {
+    pub fn new(address: String, weight: u32) -> Self {
+        Backend {
+            address,
+            weight: AtomicU32::new(weight),
+            score: AtomicI64::new(0),
+            active_connections: AtomicUsize::new(0),
+            healthy: AtomicBool::new(true),
+            draining: AtomicBool::new(false),
+            passes: AtomicU32::new(0),
+            failures: AtomicU32::new(0),
+            last_check: Mutex::new(CheckRecord::default()),
+            http_check: RwLock::new(None),
+            outlier: Mutex::new(OutlierState::default()),
+            ejected_until: AtomicU64::new(0),
+            connections: ConnectionPool::default(),
+            stats: BackendStats::default(),
+        }
+    }
+
+    pub fn from_config(config: &BackendConfig) -> Self {
+        let backend = Backend::new(config.address.get_ref().to_string(), config.weight);
+        backend.set_http_check(config.health_check.clone());
+        backend
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.weight.load(Ordering::Relaxed)
+    }
+
+    pub fn set_weight(&self, weight: u32) {
+        self.weight.store(weight, Ordering::Relaxed);
+    }
+
+    pub fn score(&self) -> &AtomicI64 {
+        &self.score
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+        if draining {
+            self.connections.clear();
+        }
+    }
+
+    /*
+     * Whether outlier detection currently has this backend ejected
+     */
+    pub fn is_ejected(&self) -> bool {
+        self.ejected_until.load(Ordering::Relaxed) > ticks(Instant::now())
+    }
+
+    pub fn ejected_until(&self) -> Option<Instant> {
+        let until = self.ejected_until.load(Ordering::Relaxed);
+        (until > ticks(Instant::now())).then(|| epoch() + Duration::from_nanos(until))
+    }
+
+    /*
+     * Idle keep-alive connections to this backend
+     */
+    pub fn connections(&self) -> &ConnectionPool {
+        &self.connections
+    }
+
+    pub fn stats(&self) -> &BackendStats {
+        &self.stats
+    }
+
+    pub fn last_check(&self) -> CheckRecord {
+        self.last_check.lock().unwrap().clone()
+    }
+
+    /*
+     * This backend's overrides for the pool's HTTP health check
+     */
+    pub fn http_check(&self) -> Option<HttpCheckConfig> {
+        self.http_check.read().unwrap().clone()
+    }
+
+    pub fn set_http_check(&self, overrides: Option<HttpCheckConfig>) {
+        *self.http_check.write().unwrap() = overrides;
+    }
+
+    /*
+     * Feeds one health check result into the rise/fall counters. Returns the
+     * new state when this result flipped the backend up or down.
+     */
+    pub fn record_check(&self, result: Result<(), String>, rise: u32, fall: u32) -> Option<bool> {
+        let passed = result.is_ok();
+        self.stats.checked(passed);
+        {
+            let now = SystemTime::now();
+            let mut last_check = self.last_check.lock().unwrap();
+            last_check.checked_at = Some(now);
+            last_check.passed = passed;
+            if let Err(reason) = result {
+                last_check.last_failure = Some((now, reason));
+            }
+        }
+
+        let (streak, other, threshold) = if passed {
+            (&self.passes, &self.failures, rise)
+        } else {
+            (&self.failures, &self.passes, fall)
+        };
+        other.store(0, Ordering::Relaxed);
+        let count = streak.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if count >= threshold && self.healthy.swap(passed, Ordering::Relaxed) != passed {
+            if !passed {
+                self.connections.clear();
+            }
+            Some(passed)
+        } else {
+            None
+        }
+    }
+
+    fn reset_health(&self) {
+        self.healthy.store(true, Ordering::Relaxed);
+        self.passes.store(0, Ordering::Relaxed);
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    fn track_connection(self: &Arc<Self>) -> ConnectionGuard {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard {
+            backend: Arc::clone(self),
+        }
+    }
+}
+
+/*
+ * Counts one connection against a backend for as long as it is in use.
+ * `Pool::select` hands one out with every pick, so it covers a single
+ * attempt at a request or a passthrough relay, and the count drops again
+ * however that ends, including on errors.
+ */
+pub struct ConnectionGuard {
+    backend: Arc<Backend>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.backend
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/*
+ * A named group of backends that one or more listeners forward to
+ */
+pub struct Pool {
+    name: String,
+    snapshot: ArcSwap<Snapshot>,
+    /*
+     * Serialises updates to `snapshot`; readers never take it
+     */
+    updating: Mutex<()>,
+    /*
+     * Serialises ejection decisions so two failing backends cannot both
+     * squeeze under `max_ejection_percent`
+     */
+    ejecting: Mutex<()>,
+    retry_budget: RetryBudget,
+}
+
+/*
+ * Everything about a pool that a reload can change. Requests load the
+ * current one without locking; updates build a new one and swap it in, so
+ * a request sees either all of a reload or none of it.
+ */
+#[derive(Clone)]
+struct Snapshot {
+    health_check: Option<HealthCheckConfig>,
+    outlier_detection: Option<OutlierDetectionConfig>,
+    retry: RetryConfig,
+    connection_pool: ConnectionPoolConfig,
+    tls: Option<UpstreamTlsConfig>,
+    proxy_protocol: Option<ProxyVersion>,
+    algorithm: Algorithm,
+    strategy: Arc<dyn BalancingStrategy>,
+    backends: Vec<Arc<Backend>>,
+}
+
+impl Pool {
+    pub fn new(name: &str, config: &PoolConfig) -> Self {
+        let backends = config
+            .backends
+            .iter()
+            .map(|backend| Arc::new(Backend::from_config(backend)))
+            .collect();
+
+        Pool {
+            name: name.to_string(),
+            snapshot: ArcSwap::from_pointee(Snapshot {
+                health_check: health_check(config),
+                outlier_detection: config.outlier_detection.clone(),
+                retry: config.retry.clone(),
+                connection_pool: config.connection_pool.clone(),
+                tls: upstream_tls(config),
+                proxy_protocol: config.proxy_protocol,
+                algorithm: config.algorithm.clone(),
+                strategy: Arc::from(strategy::build(&config.algorithm)),
+                backends,
+            }),
+            updating: Mutex::new(()),
+            ejecting: Mutex::new(()),
+            retry_budget: RetryBudget::default(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn health_check(&self) -> Option<HealthCheckConfig> {
+        self.snapshot.load().health_check.clone()
+    }
+
+    pub fn retry(&self) -> RetryConfig {
+        self.snapshot.load().retry.clone()
+    }
+
+    pub fn connection_pool(&self) -> ConnectionPoolConfig {
+        self.snapshot.load().connection_pool.clone()
+    }
+
+    /*
+     * How to speak TLS to the backends, if at all
+     */
+    pub fn tls(&self) -> Option<UpstreamTlsConfig> {
+        self.snapshot.load().tls.clone()
+    }
+
+    pub fn proxy_protocol(&self) -> Option<ProxyVersion> {
+        self.snapshot.load().proxy_protocol
+    }
+
+    /*
+     * Closes the idle connections of every backend that have outstayed the
+     * pool's limits
+     */
+    pub fn evict_idle(&self) {
+        let snapshot = self.snapshot.load();
+        for backend in &snapshot.backends {
+            backend.connections.evict(&snapshot.connection_pool);
+        }
+    }
+
+    pub fn retry_budget(&self) -> &RetryBudget {
+        &self.retry_budget
+    }
+
+    /*
+     * Every backend in the pool, healthy or not
+     */
+    pub fn backends(&self) -> Vec<Arc<Backend>> {
+        self.snapshot.load().backends.clone()
+    }
+
+    pub fn backend(&self, address: &str) -> Option<Arc<Backend>> {
+        self.snapshot
+            .load()
+            .backends
+            .iter()
+            .find(|backend| backend.address() == address)
+            .cloned()
+    }
+
+    /*
+     * Adds a backend outside of the config, until the next reload. Returns
+     * false when the pool already has one at that address.
+     */
+    pub fn add_backend(&self, backend: Backend) -> bool {
+        let _updating = self.updating.lock().unwrap();
+        let current = self.snapshot.load_full();
+        if current
+            .backends
+            .iter()
+            .any(|existing| existing.address() == backend.address())
+        {
+            return false;
+        }
+        eprintln!("Adding backend {} to pool {}", backend.address(), self.name);
+        let mut snapshot = Snapshot::clone(&current);
+        snapshot.backends.push(Arc::new(backend));
+        self.snapshot.store(Arc::new(snapshot));
+        true
+    }
+
+    /*
+     * Takes a backend out of the pool until the next reload. Relays already
+     * using it run to completion, as with one removed from the config.
+     */
+    pub fn remove_backend(&self, address: &str) -> Option<Arc<Backend>> {
+        let _updating = self.updating.lock().unwrap();
+        let current = self.snapshot.load_full();
+        let mut snapshot = Snapshot::clone(&current);
+        let index = snapshot
+            .backends
+            .iter()
+            .position(|backend| backend.address() == address)?;
+        let backend = snapshot.backends.remove(index);
+        backend.connections.clear();
+        eprintln!(
+            "Draining backend {} from pool {} ({} active connections)",
+            address,
+            self.name,
+            backend.active_connections()
+        );
+        self.snapshot.store(Arc::new(snapshot));
+        Some(backend)
+    }
+
+    /*
+     * Asks the pool's strategy for one of the healthy backends, skipping
+     * those that are down, ejected or draining and those in `tried`.
+     * Returns None when none is left. Nothing is locked on the way, so
+     * concurrent picks may not yet see each other's connection in the
+     * counts.
+     */
+    pub fn select(
+        &self,
+        context: &RequestContext,
+        tried: &[String],
+    ) -> Option<(Arc<Backend>, ConnectionGuard)> {
+        let snapshot = self.snapshot.load();
+        let outlier_detection = snapshot.outlier_detection.is_some();
+        // Taken rather than borrowed, in case a custom strategy picks from
+        // another pool on the same thread
+        let mut eligible = ELIGIBLE.take();
+        eligible.extend(
+            snapshot
+                .backends
+                .iter()
+                .filter(|backend| backend.is_healthy() && !backend.is_draining())
+                .filter(|backend| !(outlier_detection && backend.is_ejected()))
+                .filter(|backend| !tried.iter().any(|address| address == backend.address()))
+                .cloned(),
+        );
+        let picked = match eligible.is_empty() {
+            true => None,
+            false => snapshot
+                .strategy
+                .select(context, &eligible)
+                .and_then(|index| eligible.get(index))
+                .cloned(),
+        };
+        eligible.clear();
+        ELIGIBLE.set(eligible);
+
+        let backend = picked?;
+        let guard = backend.track_connection();
+        Some((backend, guard))
+    }
+
+    pub fn connected(&self, backend: &Backend) {
+        self.snapshot.load().strategy.on_connect(backend);
+    }
+
+    pub fn completed(&self, backend: &Backend, outcome: &Outcome) {
+        backend.stats.completed(outcome);
+        self.snapshot.load().strategy.on_complete(backend, outcome);
+    }
+
+    /*
+     * Feeds live traffic into outlier detection and ejects the backend when
+     * it crosses a threshold, unless that would take out more of the pool
+     * than `max_ejection_percent` allows or leave no backend at all
+     */
+    pub fn observe(&self, backend: &Backend, observation: Observation) {
+        let snapshot = self.snapshot.load_full();
+        let Some(config) = &snapshot.outlier_detection else {
+            return;
+        };
+
+        let now = Instant::now();
+        let reason = backend
+            .outlier
+            .lock()
+            .unwrap()
+            .observe(observation, config, now);
+        let Some(reason) = reason else {
+            return;
+        };
+
+        let _ejecting = self.ejecting.lock().unwrap();
+        let backends = &snapshot.backends;
+        let ejected = backends
+            .iter()
+            .filter(|other| other.address() != backend.address() && other.is_ejected())
+            .count();
+        let allowed = (ejected + 1) * 100
+            <= backends.len() * usize::from(config.max_ejection_percent)
+            && ejected + 1 < backends.len();
+
+        let mut state = backend.outlier.lock().unwrap();
+        if allowed {
+            let duration = state.eject(config, now);
+            backend
+                .ejected_until
+                .store(ticks(now + duration), Ordering::Relaxed);
+            eprintln!(
+                "Ejecting backend {} from pool {} for {:?} after {}",
+                backend.address(),
+                self.name,
+                duration,
+                reason
+            );
+        } else {
+            state.reset_counters();
+            eprintln!(
+                "Not ejecting backend {} from pool {} after {}: {} of {} backends already ejected",
+                backend.address(),
+                self.name,
+                reason,
+                ejected,
+                backends.len()
+            );
+        }
+    }
+
+    /*
+     * Brings the pool in line with a reloaded config. Backends that are kept
+     * retain their counters and place in the rotation; removed ones stop
+     * receiving new connections while the relays already using them run to
+     * completion. Drain flags set through the admin API survive. The
+     * strategy is only rebuilt, losing its state, when the algorithm itself
+     * changes.
+     */
+    pub fn reconcile(&self, config: &PoolConfig) {
+        let name = self.name.as_str();
+        let _updating = self.updating.lock().unwrap();
+        let current = self.snapshot.load_full();
+
+        let health_check = health_check(config);
+        if current.health_check.is_some() && health_check.is_none() {
+            // Nothing will bring a down backend back up any more
+            for backend in &current.backends {
+                backend.reset_health();
+            }
+        }
+
+        let tls = upstream_tls(config);
+        if current.tls.is_some() || tls.is_some() {
+            // Idle connections were set up with the old TLS settings
+            for backend in &current.backends {
+                backend.connections.clear();
+            }
+        }
+
+        let strategy = if current.algorithm != config.algorithm {
+            eprintln!(
+                "Switching pool {} from {} to {}",
+                name, current.algorithm, config.algorithm
+            );
+            for backend in &current.backends {
+                backend.score.store(0, Ordering::Relaxed);
+            }
+            Arc::from(strategy::build(&config.algorithm))
+        } else {
+            Arc::clone(&current.strategy)
+        };
+
+        let mut backends: Vec<Arc<Backend>> = current
+            .backends
+            .iter()
+            .filter(|backend| {
+                let keep = config
+                    .backends
+                    .iter()
+                    .any(|wanted| wanted.address.get_ref().as_str() == backend.address());
+                if !keep {
+                    backend.connections.clear();
+                    eprintln!(
+                        "Draining backend {} from pool {} ({} active connections)",
+                        backend.address(),
+                        name,
+                        backend.active_connections()
+                    );
+                }
+                keep
+            })
+            .cloned()
+            .collect();
+
+        for wanted in &config.backends {
+            let address = wanted.address.get_ref().as_str();
+            match backends.iter().find(|backend| backend.address() == address) {
+                Some(backend) => {
+                    backend.set_http_check(wanted.health_check.clone());
+                    if backend.weight() != wanted.weight {
+                        eprintln!(
+                            "Changing weight of backend {} in pool {} from {} to {}",
+                            address,
+                            name,
+                            backend.weight(),
+                            wanted.weight
+                        );
+                        backend.set_weight(wanted.weight);
+                    }
+                }
+                None => {
+                    eprintln!("Adding backend {} to pool {}", address, name);
+                    backends.push(Arc::new(Backend::from_config(wanted)));
+                }
+            }
+        }
+
+        self.snapshot.store(Arc::new(Snapshot {
+            health_check,
+            outlier_detection: config.outlier_detection.clone(),
+            retry: config.retry.clone(),
+            connection_pool: config.connection_pool.clone(),
+            tls,
+            proxy_protocol: config.proxy_protocol,
+            algorithm: config.algorithm.clone(),
+            strategy,
+            backends,
+        }));
+    }
+}
+
+thread_local! {
+    /*
+     * The candidates of a pick, kept per worker thread so that picking
+     * allocates nothing once the buffer has grown to the pool size
+     */
+    static ELIGIBLE: Cell<Vec<Arc<Backend>>> = const { Cell::new(Vec::new()) };
+}
+
+/*
+ * Instants as nanoseconds since the first one taken, to fit in an atomic
+ */
+fn ticks(instant: Instant) -> u64 {
+    instant.saturating_duration_since(epoch()).as_nanos() as u64
+}
+
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+fn upstream_tls(config: &PoolConfig) -> Option<UpstreamTlsConfig> {
+    config.tls.as_ref().map(|tls| tls.get_ref().clone())
+}
+
+fn health_check(config: &PoolConfig) -> Option<HealthCheckConfig> {
+    config
+        .health_check
+        .as_ref()
+        .map(|health_check| health_check.get_ref().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(backends: usize, outlier_detection: &str) -> Pool {
+        let mut source = format!("[outlier_detection]\n{}\n", outlier_detection);
+        for i in 1..=backends {
+            source += &format!("[[backend]]\naddress = \"10.0.0.{}:80\"\n", i);
+        }
+        let config: PoolConfig = toml::from_str(&source).unwrap();
+        Pool::new("test", &config)
+    }
+
+    fn ejected(pool: &Pool) -> Vec<bool> {
+        pool.backends()
+            .iter()
+            .map(|backend| backend.is_ejected())
+            .collect()
+    }
+
+    #[test]
+    fn ejects_no_more_than_max_ejection_percent() {
+        let pool = pool(4, "consecutive_5xx = 1\nmax_ejection_percent = 50");
+        for backend in pool.backends() {
+            pool.observe(&backend, Observation::Response(500));
+        }
+        assert_eq!(ejected(&pool), [true, true, false, false]);
+    }
+
+    #[test]
+    fn never_ejects_the_last_backend() {
+        let pool = pool(1, "consecutive_5xx = 1\nmax_ejection_percent = 100");
+        let backend = &pool.backends()[0];
+        for _ in 0..3 {
+            pool.observe(backend, Observation::Response(500));
+        }
+        assert_eq!(ejected(&pool), [false]);
+
+        let pool = self::pool(2, "consecutive_5xx = 1\nmax_ejection_percent = 100");
+        for backend in pool.backends() {
+            pool.observe(&backend, Observation::Response(500));
+        }
+        assert_eq!(ejected(&pool), [true, false]);
+    }
+
+    #[test]
+    fn ejection_lasts_the_backed_off_duration() {
+        let pool = pool(
+            2,
+            "consecutive_5xx = 1\nbase_ejection_ms = 10000\nmax_ejection_ms = 15000",
+        );
+        let backend = &pool.backends()[0];
+        pool.observe(backend, Observation::Response(500));
+        let until = backend.ejected_until().unwrap();
+        let remaining = until.saturating_duration_since(Instant::now());
+        assert!(remaining > Duration::from_secs(9) && remaining <= Duration::from_secs(10));
+    }
+
+    #[test]
+    fn select_skips_ejected_draining_and_tried_backends() {
+        let pool = pool(4, "consecutive_5xx = 1\nmax_ejection_percent = 50");
+        let backends = pool.backends();
+        pool.observe(&backends[0], Observation::Response(500));
+        backends[1].set_draining(true);
+        let context = RequestContext {
+            client_addr: "192.0.2.1:40000".parse().unwrap(),
+        };
+
+        for _ in 0..8 {
+            let (backend, _guard) = pool.select(&context, &[]).unwrap();
+            assert!(["10.0.0.3:80", "10.0.0.4:80"].contains(&backend.address()));
+        }
+        let tried = ["10.0.0.3:80".to_string()];
+        for _ in 0..4 {
+            let (backend, _guard) = pool.select(&context, &tried).unwrap();
+            assert_eq!(backend.address(), "10.0.0.4:80");
+        }
+        let tried = ["10.0.0.3:80".to_string(), "10.0.0.4:80".to_string()];
+        assert!(pool.select(&context, &tried).is_none());
+
+        backends[1].set_draining(false);
+        let (backend, _guard) = pool.select(&context, &tried).unwrap();
+        assert_eq!(backend.address(), "10.0.0.2:80");
+    }
+}
@@ -0,0 +1,1390 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    path::Path,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwap;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    runtime::{self, Handle},
+    task, time,
+};
+
+use crate::{
+    access::{AccessLog, Entry, Termination},
+    admin,
+    backend::{Backend, Pool},
+    config::{Config, ConfigError, ListenerMode, PoolConfig, ProxyProtocolConfig},
+    health,
+    http::{self, BodyLength, Forwarding, HeadState, RequestHead, ResponseHead},
+    metrics::{self, TOTALS},
+    outlier::Observation,
+    proxy::{self, Addresses},
+    relay::{
+        copy_body, is_timeout, read_head, relay, BodyError, Limits, Preamble, RelayError, Stop,
+        TimeoutCause,
+    },
+    router,
+    shutdown::Shutdown,
+    sni::{self, ClientHello},
+    strategy::{Outcome, RequestContext},
+    stream::Stream,
+    tls,
+    upstream::{self, Connection},
+};
+use tokio_rustls::TlsAcceptor;
+
+pub struct LoadBalancer {
+    config: Arc<ArcSwap<Config>>,
+    pools: Arc<ArcSwap<HashMap<String, Arc<Pool>>>>,
+    /*
+     * Keeps a SIGHUP and a file change from reloading at the same time
+     */
+    reloading: Arc<Mutex<()>>,
+    access_log: Arc<AccessLog>,
+    shutdown: Arc<Shutdown>,
+    /*
+     * The runtime `run` serves on, which health checks of pools added by a
+     * reload are spawned on too
+     */
+    runtime: Arc<OnceLock<Handle>>,
+}
+
+impl LoadBalancer {
+    pub fn new(config: &Config) -> Self {
+        let pools = config
+            .pools
+            .iter()
+            .map(|(name, pool)| (name.clone(), spawn_pool(None, name, pool)))
+            .collect();
+
+        LoadBalancer {
+            config: Arc::new(ArcSwap::from_pointee(config.clone())),
+            pools: Arc::new(ArcSwap::from_pointee(pools)),
+            reloading: Arc::new(Mutex::new(())),
+            access_log: Arc::new(AccessLog::new(&config.access_log)),
+            shutdown: Arc::new(Shutdown::default()),
+            runtime: Arc::new(OnceLock::new()),
+        }
+    }
+
+    /*
+     * Snapshot of the config currently in effect
+     */
+    pub fn config(&self) -> Arc<Config> {
+        self.config.load_full()
+    }
+
+    /*
+     * Stops accepting connections and closes idle ones, after which `run`
+     * waits for the rest up to `shutdown.drain_timeout_ms` and returns.
+     * Returns false when a shutdown was already under way.
+     */
+    pub fn shutdown(&self) -> bool {
+        self.shutdown.request()
+    }
+
+    /*
+     * Reopens the access log file after logrotate moved it away
+     */
+    pub fn reopen_access_log(&self) -> io::Result<()> {
+        self.access_log.reopen()
+    }
+
+    /*
+     * Writes the entry unless the request never got going, as when a
+     * keep-alive connection is closed between requests
+     */
+    fn log(&self, entry: &Entry) {
+        if entry.status.is_some() || entry.termination.is_some() {
+            self.access_log.write(entry);
+        }
+    }
+
+    pub fn pools(&self) -> Arc<HashMap<String, Arc<Pool>>> {
+        self.pools.load_full()
+    }
+
+    pub fn pool(&self, name: &str) -> Option<Arc<Pool>> {
+        self.pools.load().get(name).cloned()
+    }
+
+    /*
+     * Re-reads the config file and applies it to the running pools. A config
+     * that fails to parse or validate is rejected and the current one stays
+     * in effect. Listeners, the metrics and admin ones included, are bound
+     * once at startup, so changes to them are only picked up on restart; a
+     * new admin token applies right away.
+     */
+    pub fn reload(&self, path: &Path) -> Result<(), ConfigError> {
+        let new_config = Config::load(path)?;
+        let _reloading = self.reloading.lock().unwrap();
+        let current = self.config();
+
+        let admin_address =
+            |config: &Config| config.admin.as_ref().map(|admin| admin.address.clone());
+        if new_config.listeners != current.listeners
+            || new_config.metrics != current.metrics
+            || admin_address(&new_config) != admin_address(&current)
+        {
+            eprintln!("Listener changes in {} require a restart", path.display());
+        }
+        for listener in current.listeners.get_ref() {
+            let Some(pool) = &listener.pool else {
+                continue;
+            };
+            let pool = pool.get_ref();
+            if !new_config.pools.contains_key(pool) {
+                return Err(ConfigError::Rejected {
+                    path: path.to_path_buf(),
+                    message: format!(
+                        "pool `{}` is still served by listener {} and cannot be removed",
+                        pool,
+                        listener.address.get_ref()
+                    ),
+                });
+            }
+        }
+        self.access_log
+            .configure(&new_config.access_log)
+            .map_err(|e| ConfigError::Rejected {
+                path: path.to_path_buf(),
+                message: format!("cannot open access log: {}", e),
+            })?;
+
+        let mut pools = HashMap::clone(&self.pools.load());
+        pools.retain(|name, _| {
+            let keep = new_config.pools.contains_key(name);
+            if !keep {
+                eprintln!("Removing pool {}", name);
+            }
+            keep
+        });
+        for (name, pool_config) in &new_config.pools {
+            match pools.get(name) {
+                Some(pool) => pool.reconcile(pool_config),
+                None => {
+                    eprintln!("Adding pool {}", name);
+                    let pool = spawn_pool(self.runtime.get(), name, pool_config);
+                    pools.insert(name.clone(), pool);
+                }
+            }
+        }
+        self.pools.store(Arc::new(pools));
+        self.config.store(Arc::new(new_config));
+        Ok(())
+    }
+
+    /*
+     * Handles the incomming client, serving one request after another for
+     * as long as both the client and the responses allow the connection to
+     * stay open
+     */
+    async fn handle_client(
+        &self,
+        mut client_stream: TcpStream,
+        entrance: &Entrance,
+    ) -> io::Result<()> {
+        let peer_addr = client_stream.peer_addr()?;
+        let mut addresses = Addresses {
+            source: peer_addr,
+            destination: client_stream.local_addr()?,
+        };
+        let proxied = entrance
+            .proxy_protocol
+            .as_ref()
+            .is_some_and(|proxy_protocol| proxy_protocol.is_trusted(peer_addr.ip()));
+        if proxied {
+            let header = proxy::read_header(&mut client_stream);
+            match time::timeout(self.config().timeouts.idle, header).await {
+                Ok(Ok(Some(proxied))) => addresses = proxied,
+                Ok(Ok(None)) => {}
+                Ok(Err(e)) => {
+                    eprintln!("Bad PROXY protocol header from {}: {}", peer_addr, e);
+                    return Ok(());
+                }
+                Err(_) => {
+                    eprintln!("{} timed out sending its PROXY protocol header", peer_addr);
+                    return Ok(());
+                }
+            }
+        }
+        let client_addr = addresses.source;
+
+        if entrance.passthrough {
+            let mut entry = Entry::new(client_addr);
+            let result = self
+                .pass_through(client_stream, &addresses, entrance, &mut entry)
+                .await;
+            self.log(&entry);
+            return result;
+        }
+        let client_stream = match &entrance.tls {
+            None => Stream::Plain(client_stream),
+            Some(acceptor) => {
+                let handshake = acceptor.accept(client_stream);
+                match time::timeout(self.config().timeouts.idle, handshake).await {
+                    Ok(Ok(stream)) => Stream::Tls(Box::new(stream.into())),
+                    Ok(Err(e)) => {
+                        eprintln!("TLS handshake with {} failed: {}", client_addr, e);
+                        return Ok(());
+                    }
+                    Err(_) => {
+                        eprintln!("TLS handshake with {} timed out", client_addr);
+                        return Ok(());
+                    }
+                }
+            }
+        };
+
+        let mut client = BufReader::new(client_stream);
+        let mut first = true;
+        loop {
+            if !first && !self.next_request(&mut client).await? {
+                return Ok(());
+            }
+            let mut entry = Entry::new(client_addr);
+            let result = self
+                .serve_request(&mut client, &addresses, entrance, first, &mut entry)
+                .await;
+            self.log(&entry);
+            if !result? {
+                return Ok(());
+            }
+            first = false;
+        }
+    }
+
+    /*
+     * Waits on a keep-alive connection for the next request to start.
+     * Returns false when the connection should close instead, because it
+     * stayed idle too long or the balancer is shutting down.
+     */
+    async fn next_request(&self, client: &mut BufReader<Stream>) -> io::Result<bool> {
+        if !client.buffer().is_empty() {
+            return Ok(true);
+        }
+        let idle = self.config().timeouts.idle;
+        tokio::select! {
+            ready = time::timeout(idle, client.fill_buf()) => match ready {
+                Ok(Ok(_)) => Ok(true),
+                Ok(Err(e)) => Err(e),
+                Err(_) => Ok(false),
+            },
+            _ = self.shutdown.requested() => Ok(false),
+        }
+    }
+
+    /*
+     * Serves a TLS passthrough connection: reads the ClientHello for its
+     * server name, picks the pool by it and relays the still encrypted
+     * connection to a backend there
+     */
+    async fn pass_through(
+        &self,
+        mut client_stream: TcpStream,
+        addresses: &Addresses,
+        entrance: &Entrance,
+        entry: &mut Entry,
+    ) -> io::Result<()> {
+        let client_addr = addresses.source;
+        let config = self.config();
+        let timeouts = &config.timeouts;
+        let limits = Limits {
+            idle: timeouts.idle,
+            first_byte: timeouts.first_byte,
+            deadline: None,
+        };
+
+        let mut hello = Vec::new();
+        let server_name = loop {
+            match sni::parse(&hello) {
+                ClientHello::Complete(server_name) => break server_name,
+                ClientHello::Partial if hello.len() < sni::MAX_HELLO => {}
+                _ => {
+                    entry.termination = Some(Termination::BadRequest);
+                    return Ok(());
+                }
+            }
+            let mut chunk = [0; 4096];
+            let read = limits
+                .within(
+                    limits.idle,
+                    TimeoutCause::Idle,
+                    client_stream.read(&mut chunk),
+                )
+                .await;
+            match read {
+                Ok(0) => return Ok(()),
+                Ok(read) => hello.extend_from_slice(&chunk[..read]),
+                Err(Stop::Timeout(_)) => {
+                    entry.termination = Some(Termination::ClientTimeout);
+                    return Ok(());
+                }
+                Err(Stop::Io(e)) => return Err(e),
+            }
+        };
+        entry.server_name = server_name.clone();
+
+        let pool_name = server_name
+            .as_deref()
+            .and_then(|name| router::route_sni(&config.sni_routes, name))
+            .or(entrance.default_pool.as_deref());
+        let Some(pool) = pool_name.and_then(|name| self.pool(name)) else {
+            entry.termination = Some(Termination::NoRoute);
+            return Ok(());
+        };
+        entry.pool = Some(pool.name().to_string());
+
+        let retry = pool.retry();
+        let connect_timeout = retry.connect_timeout.unwrap_or(timeouts.connect);
+        let proxy_header = pool
+            .proxy_protocol()
+            .map(|version| proxy::header(version, addresses));
+        let context = RequestContext { client_addr };
+        let _request = pool.retry_budget().start_request();
+        let mut retry_slot;
+        let mut tried = Vec::new();
+        let (backend, _connection, mut backend_stream) = loop {
+            let Some((backend, connection)) = pool.select(&context, &tried) else {
+                entry.termination = Some(if tried.is_empty() {
+                    Termination::NoBackend
+                } else {
+                    Termination::BackendFailed
+                });
+                return Ok(());
+            };
+            tried.push(backend.address().to_string());
+            entry.backend = Some(backend.address().to_string());
+            entry.attempts += 1;
+            let connecting = Instant::now();
+            let e =
+                match upstream::open(backend.address(), connect_timeout, proxy_header.as_deref())
+                    .await
+                {
+                    Ok(stream) => {
+                        entry.connect_time = Some(connecting.elapsed());
+                        break (backend, connection, stream);
+                    }
+                    Err(e) => e,
+                };
+            pool.observe(&backend, Observation::ConnectFailure);
+            pool.completed(
+                &backend,
+                &Outcome {
+                    success: false,
+                    status: None,
+                    duration: connecting.elapsed(),
+                },
+            );
+            retry_slot = if (tried.len() as u32) <= retry.attempts {
+                pool.retry_budget().try_retry(&retry)
+            } else {
+                None
+            };
+            if retry_slot.is_none() {
+                entry.termination = Some(Termination::BackendFailed);
+                return Ok(());
+            }
+            eprintln!(
+                "Connecting to backend {} failed: {}, retrying on another backend",
+                backend.address(),
+                e
+            );
+        };
+        pool.connected(&backend);
+        pool.observe(&backend, Observation::Connected);
+
+        let started = Instant::now();
+        let relayed = match limits
+            .within(
+                limits.idle,
+                TimeoutCause::Idle,
+                backend_stream.write_all(&hello),
+            )
+            .await
+        {
+            Ok(()) => {
+                let preamble = Preamble {
+                    request_bytes: hello.len() as u64,
+                    response: Vec::new(),
+                };
+                let mut client = Stream::Plain(client_stream);
+                relay(&mut client, Stream::Plain(backend_stream), preamble, limits).await
+            }
+            Err(stop) => Err(RelayError::Backend(stop_error(stop))),
+        };
+        let success = relayed
+            .as_ref()
+            .is_ok_and(|summary| summary.timeout.is_none());
+        pool.completed(
+            &backend,
+            &Outcome {
+                success,
+                status: None,
+                duration: started.elapsed(),
+            },
+        );
+        let summary = relayed.map_err(|error| {
+            entry.termination = Some(match error {
+                RelayError::Client(_) => Termination::ClientAborted,
+                RelayError::Backend(_) => Termination::BackendAborted,
+            });
+            io::Error::from(error)
+        })?;
+        backend
+            .stats()
+            .transferred(summary.bytes_to_backend, summary.bytes_to_client);
+        entry.bytes = summary.bytes_to_client;
+        entry.termination = Some(match summary.timeout {
+            Some(cause) => Termination::timed_out(cause),
+            None => Termination::Relayed,
+        });
+        Ok(())
+    }
+
+    /*
+     * Reads one request from the client and sends back the response of a
+     * backend. Returns whether the connection can carry another request.
+     */
+    async fn serve_request(
+        &self,
+        client: &mut BufReader<Stream>,
+        addresses: &Addresses,
+        entrance: &Entrance,
+        first: bool,
+        entry: &mut Entry,
+    ) -> io::Result<bool> {
+        let client_addr = addresses.source;
+        let config = self.config();
+        let timeouts = &config.timeouts;
+        // The request deadline starts once its first byte arrives
+        let mut limits = Limits {
+            idle: timeouts.idle,
+            first_byte: timeouts.first_byte,
+            deadline: None,
+        };
+
+        // A fresh connection may sit silent, as after a browser preconnect;
+        // it must not hold up shutdown any more than an idle keep-alive one
+        if first && client.buffer().is_empty() {
+            tokio::select! {
+                ready = time::timeout(timeouts.idle, client.fill_buf()) => match ready {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => return Err(e),
+                    Err(_) => {
+                        entry.start();
+                        return respond(client, entry, REQUEST_TIMEOUT).await;
+                    }
+                },
+                _ = self.shutdown.requested() => return Ok(false),
+            }
+        }
+
+        let mut head = Vec::new();
+        let state = read_head(
+            client,
+            &mut head,
+            RequestHead::state,
+            &mut limits,
+            (timeouts.idle, TimeoutCause::Idle),
+            timeouts.request,
+        )
+        .await;
+        entry.start();
+        let request = match state {
+            Ok(HeadState::Complete) => RequestHead::parse(&head),
+            Ok(HeadState::Partial) if head.len() < http::MAX_HEAD => return Ok(false),
+            Ok(HeadState::Partial) => return respond(client, entry, HEADERS_TOO_LARGE).await,
+            Ok(HeadState::Invalid) => None,
+            // An idle keep-alive connection is simply closed
+            Err(Stop::Timeout(_)) if head.is_empty() && !first => return Ok(false),
+            Err(Stop::Timeout(_)) => return respond(client, entry, REQUEST_TIMEOUT).await,
+            Err(Stop::Io(e)) => return Err(e),
+        };
+        TOTALS.request();
+        let Some((request, body_length)) =
+            request.and_then(|request| Some((request.clone(), request.body_length()?)))
+        else {
+            return respond(client, entry, BAD_REQUEST).await;
+        };
+        entry.request(&request);
+        let Some(pool_name) =
+            router::route(&config.routes, &request).or(entrance.default_pool.as_deref())
+        else {
+            return respond(client, entry, NOT_FOUND).await;
+        };
+        entry.pool = Some(pool_name.to_string());
+        let Some(pool) = self.pool(pool_name) else {
+            return respond(client, entry, SERVICE_UNAVAILABLE).await;
+        };
+
+        if request.expects_continue() && body_length != BodyLength::Fixed(0) {
+            client.get_mut().write_all(CONTINUE.as_bytes()).await?;
+        }
+
+        // Small idempotent requests are read in full up front, so that they
+        // can be sent again if a backend fails before answering
+        let body = match body_length {
+            BodyLength::Fixed(length) if request.is_idempotent() && length <= MAX_REPLAY_BODY => {
+                let mut body = Vec::with_capacity(length as usize);
+                match copy_body(client, &mut body, body_length, &limits).await {
+                    Ok(_) => Some(body),
+                    Err(BodyError::Read(Stop::Timeout(_)))
+                    | Err(BodyError::Write(Stop::Timeout(_))) => {
+                        return respond(client, entry, REQUEST_TIMEOUT).await;
+                    }
+                    Err(BodyError::Read(Stop::Io(e))) | Err(BodyError::Write(Stop::Io(e))) => {
+                        return Err(e)
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        let retry = pool.retry();
+        let proxy_header = pool
+            .proxy_protocol()
+            .map(|version| proxy::header(version, addresses));
+        let reuse = pool.connection_pool().max_idle > 0 && proxy_header.is_none();
+        let exchange = Exchange {
+            backend_head: request.for_backend(
+                &head,
+                &Forwarding {
+                    client: client_addr.ip(),
+                    proto: if entrance.tls.is_some() {
+                        "https"
+                    } else {
+                        "http"
+                    },
+                    trusted: config.forwarding.is_trusted(client_addr.ip()),
+                },
+                reuse,
+            ),
+            request,
+            body,
+            body_length,
+            proxy_header,
+            connect_timeout: retry.connect_timeout.unwrap_or(timeouts.connect),
+            limits,
+            shutdown: Arc::clone(&self.shutdown),
+        };
+        let context = RequestContext { client_addr };
+        let _request = pool.retry_budget().start_request();
+        // Holds the budget slot of the retry in progress, if any
+        let mut retry_slot;
+        let mut tried = Vec::new();
+
+        loop {
+            let Some((backend, _connection)) = pool.select(&context, &tried) else {
+                if tried.is_empty() {
+                    return respond(client, entry, SERVICE_UNAVAILABLE).await;
+                }
+                return respond(client, entry, BAD_GATEWAY).await;
+            };
+            tried.push(backend.address().to_string());
+            entry.backend = Some(backend.address().to_string());
+            entry.attempts += 1;
+
+            let failure = match forward(&pool, &backend, client, &exchange, entry).await {
+                Ok(keep_alive) => return Ok(keep_alive),
+                Err(AttemptError::Aborted(e)) => {
+                    entry.termination.get_or_insert(Termination::ClientAborted);
+                    return Err(e);
+                }
+                Err(AttemptError::ClientTimeout) => {
+                    return respond(client, entry, REQUEST_TIMEOUT).await;
+                }
+                Err(AttemptError::Retryable(failure)) => failure,
+                Err(AttemptError::Failed(failure)) => {
+                    return respond(client, entry, failure.response()).await;
+                }
+            };
+
+            let attempts_left = (tried.len() as u32) <= retry.attempts;
+            let deadline_passed = failure.timeout == Some(TimeoutCause::Deadline);
+            retry_slot = if attempts_left && !deadline_passed {
+                pool.retry_budget().try_retry(&retry)
+            } else {
+                None
+            };
+            if retry_slot.is_none() {
+                return respond(client, entry, failure.response()).await;
+            }
+            eprintln!(
+                "Backend {} failed: {}, retrying on another backend",
+                backend.address(),
+                failure
+            );
+        }
+    }
+
+    /*
+     * Runs the load balancer on a multi-threaded runtime with one worker per
+     * CPU, blocking for as long as it serves
+     */
+    pub fn start(&self) -> io::Result<()> {
+        runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?
+            .block_on(self.run())
+    }
+
+    /*
+     * Binds every configured listener up front so a bad address fails
+     * startup, then accepts on all of them. For embedding in an existing
+     * runtime; `start` sets one up.
+     */
+    pub async fn run(&self) -> io::Result<()> {
+        self.access_log(AccessLog::reopen).await?;
+        {
+            // Under the reload lock, so a pool a reload adds meanwhile is
+            // either seen here or sees the runtime
+            let _reloading = self.reloading.lock().unwrap();
+            let runtime = Handle::current();
+            if self.runtime.set(runtime.clone()).is_ok() {
+                for pool in self.pools.load().values() {
+                    health::spawn(&runtime, pool);
+                }
+            }
+        }
+        let mut bound = Vec::new();
+        let mut certificates = Vec::new();
+        for listener_config in self.config().listeners.get_ref() {
+            let tls = match &listener_config.tls {
+                Some(tls_config) => {
+                    let (acceptor, listener_certificates) = tls::acceptor(tls_config.get_ref())?;
+                    certificates.push(listener_certificates);
+                    Some(acceptor)
+                }
+                None => None,
+            };
+            let listener = TcpListener::bind(listener_config.address.get_ref().as_str()).await?;
+            let entrance = Entrance {
+                default_pool: listener_config
+                    .pool
+                    .as_ref()
+                    .map(|pool| pool.get_ref().clone()),
+                tls,
+                passthrough: listener_config.mode == ListenerMode::TlsPassthrough,
+                proxy_protocol: listener_config.proxy_protocol.clone(),
+            };
+            eprintln!(
+                "Load balancer listening on {}{} (default pool {})",
+                listener_config.address.get_ref(),
+                if entrance.tls.is_some() {
+                    " with TLS"
+                } else if entrance.passthrough {
+                    " with TLS passthrough"
+                } else {
+                    ""
+                },
+                entrance.default_pool.as_deref().unwrap_or("none")
+            );
+            bound.push((listener, Arc::new(entrance)));
+        }
+        tls::spawn_watcher(certificates);
+
+        if let Some(metrics) = &self.config().metrics {
+            let listener = TcpListener::bind(metrics.address.as_str()).await?;
+            eprintln!("Serving metrics on {}", metrics.address);
+            let balancer = self.clone();
+            tokio::spawn(async move { balancer.serve_metrics(listener).await });
+        }
+
+        if let Some(admin) = &self.config().admin {
+            let listener = TcpListener::bind(admin.address.as_str()).await?;
+            eprintln!("Serving the admin API on {}", admin.address);
+            tokio::spawn(admin::serve(self.clone(), listener));
+        }
+
+        let balancer = self.clone();
+        tokio::spawn(async move { balancer.evict_idle_connections().await });
+
+        let handles: Vec<_> = bound
+            .into_iter()
+            .map(|(listener, entrance)| {
+                let balancer = self.clone();
+                tokio::spawn(async move { balancer.accept_loop(listener, entrance).await })
+            })
+            .collect();
+
+        for handle in handles {
+            let _ = handle.await;
+        }
+        self.drain().await;
+        self.access_log(AccessLog::flush).await
+    }
+
+    /*
+     * Runs a call that waits on the access log writer off the runtime's
+     * workers
+     */
+    async fn access_log(&self, call: fn(&AccessLog) -> io::Result<()>) -> io::Result<()> {
+        let access_log = Arc::clone(&self.access_log);
+        task::spawn_blocking(move || call(&access_log))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /*
+     * Gives the connections still open after the listeners closed until the
+     * drain deadline to finish. Whatever is left is cut off when the runtime
+     * shuts down.
+     */
+    async fn drain(&self) {
+        let deadline = Instant::now() + self.config().shutdown.drain_timeout;
+        eprintln!(
+            "Listeners closed, waiting for {} connections",
+            self.shutdown.open_connections()
+        );
+        while self.shutdown.open_connections() > 0 && Instant::now() < deadline {
+            time::sleep(DRAIN_POLL).await;
+        }
+        match self.shutdown.open_connections() {
+            0 => eprintln!("All connections finished, shutting down"),
+            open => eprintln!("Drain deadline passed, force-closing {} connections", open),
+        }
+    }
+
+    /*
+     * Closes pooled backend connections as they run into their idle timeout
+     * or lifetime, rather than only when next taken from the pool
+     */
+    async fn evict_idle_connections(&self) {
+        let mut interval = time::interval(EVICT_INTERVAL);
+        loop {
+            interval.tick().await;
+            for pool in self.pools.load().values() {
+                pool.evict_idle();
+            }
+        }
+    }
+
+    async fn serve_metrics(&self, listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let balancer = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = balancer.scrape(stream).await {
+                            eprintln!("Error serving metrics: {}", e)
+                        }
+                    });
+                }
+                Err(e) => {
+                    eprintln!("Error accepting connection {}", e);
+                    time::sleep(ACCEPT_BACKOFF).await;
+                }
+            }
+        }
+    }
+
+    /*
+     * Answers one request on the metrics listener: `GET /metrics` gets the
+     * current values, anything else a 404
+     */
+    async fn scrape(&self, stream: TcpStream) -> io::Result<()> {
+        let config = self.config();
+        let timeouts = &config.timeouts;
+        let mut limits = Limits {
+            idle: timeouts.idle,
+            first_byte: timeouts.first_byte,
+            deadline: None,
+        };
+        let mut reader = BufReader::new(stream);
+        let mut head = Vec::new();
+        let state = read_head(
+            &mut reader,
+            &mut head,
+            RequestHead::state,
+            &mut limits,
+            (timeouts.idle, TimeoutCause::Idle),
+            timeouts.request,
+        )
+        .await
+        .map_err(stop_error)?;
+        let request = match state {
+            HeadState::Complete => RequestHead::parse(&head),
+            _ => None,
+        };
+
+        let response = match request {
+            Some(request)
+                if request.method == "GET"
+                    && request.path.split('?').next() == Some("/metrics") =>
+            {
+                let body = metrics::render(&self.pools.load());
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            }
+            Some(_) => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string(),
+            None => BAD_REQUEST.to_string(),
+        };
+        let stream = reader.get_mut();
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+
+    /*
+     * Accepts connections until a shutdown is requested, which closes the
+     * listener
+     */
+    async fn accept_loop(&self, listener: TcpListener, entrance: Arc<Entrance>) {
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = self.shutdown.requested() => return,
+            };
+            match accepted {
+                Ok((client_stream, _)) => {
+                    TOTALS.connection_accepted();
+                    let open = self.shutdown.track();
+                    let balancer = self.clone();
+                    let entrance = Arc::clone(&entrance);
+                    tokio::spawn(async move {
+                        if let Err(e) = balancer.handle_client(client_stream, &entrance).await {
+                            eprintln!("Error handling client: {}", e)
+                        }
+                        TOTALS.connection_closed();
+                        drop(open);
+                    });
+                }
+                Err(e) => {
+                    eprintln!("Error accepting connection {}", e);
+                    // Typically out of file descriptors; give connections a
+                    // moment to close instead of spinning
+                    time::sleep(ACCEPT_BACKOFF).await;
+                }
+            }
+        }
+    }
+}
+
+impl Clone for LoadBalancer {
+    fn clone(&self) -> Self {
+        LoadBalancer {
+            config: Arc::clone(&self.config),
+            pools: Arc::clone(&self.pools),
+            reloading: Arc::clone(&self.reloading),
+            access_log: Arc::clone(&self.access_log),
+            shutdown: Arc::clone(&self.shutdown),
+            runtime: Arc::clone(&self.runtime),
+        }
+    }
+}
+
+/*
+ * What a listener hands each connection it accepts
+ */
+struct Entrance {
+    /*
+     * Pool for requests no route matches
+     */
+    default_pool: Option<String>,
+    tls: Option<TlsAcceptor>,
+    /*
+     * Relays connections still encrypted, routed on their SNI
+     */
+    passthrough: bool,
+    proxy_protocol: Option<ProxyProtocolConfig>,
+}
+
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+const EVICT_INTERVAL: Duration = Duration::from_secs(1);
+const DRAIN_POLL: Duration = Duration::from_millis(100);
+
+/*
+ * Largest body of an idempotent request that is buffered so the request can
+ * be retried
+ */
+const MAX_REPLAY_BODY: u64 = 64 * 1024;
+
+const CONTINUE: &str = "HTTP/1.1 100 Continue\r\n\r\n";
+const BAD_REQUEST: &str = "HTTP/1.1 400 Bad Request\r\n\r\nMalformed request";
+const NOT_FOUND: &str = "HTTP/1.1 404 Not Found\r\n\r\nNo route for request";
+const REQUEST_TIMEOUT: &str = "HTTP/1.1 408 Request Timeout\r\n\r\nRequest not received in time";
+const HEADERS_TOO_LARGE: &str =
+    "HTTP/1.1 431 Request Header Fields Too Large\r\n\r\nRequest head too large";
+const BAD_GATEWAY: &str = "HTTP/1.1 502 Bad Gateway\r\n\r\nBackend server unavailable";
+const SERVICE_UNAVAILABLE: &str =
+    "HTTP/1.1 503 Service Unavailable\r\n\r\nNo backend servers available";
+const GATEWAY_TIMEOUT: &str =
+    "HTTP/1.1 504 Gateway Timeout\r\n\r\nBackend server did not respond in time";
+
+/*
+ * Sends one of the balancer's own responses, after which the connection is
+ * closed since the rest of the request may still be unread
+ */
+async fn respond(
+    client: &mut BufReader<Stream>,
+    entry: &mut Entry,
+    response: &str,
+) -> io::Result<bool> {
+    TOTALS.responded(response);
+    entry.responded(response);
+    let client_stream = client.get_mut();
+    client_stream.write_all(response.as_bytes()).await?;
+    let _ = client_stream.shutdown().await;
+    Ok(false)
+}
+
+/*
+ * A parsed client request and the limits for forwarding it
+ */
+struct Exchange {
+    request: RequestHead,
+    /*
+     * The head as the backend gets it
+     */
+    backend_head: Vec<u8>,
+    /*
+     * The whole body when it was read up front, which makes the request
+     * replayable. Otherwise it is streamed from the client.
+     */
+    body: Option<Vec<u8>>,
+    body_length: BodyLength,
+    /*
+     * Opens each backend connection when the pool sends PROXY protocol
+     */
+    proxy_header: Option<Vec<u8>>,
+    connect_timeout: Duration,
+    limits: Limits,
+    /*
+     * Responses that finish after a shutdown was requested close the
+     * connection
+     */
+    shutdown: Arc<Shutdown>,
+}
+
+/*
+ * Why an attempt failed before anything reached the client
+ */
+struct Failure {
+    reason: String,
+    timeout: Option<TimeoutCause>,
+}
+
+impl Failure {
+    fn new(reason: String) -> Self {
+        Failure {
+            reason,
+            timeout: None,
+        }
+    }
+
+    fn timed_out(cause: TimeoutCause) -> Self {
+        Failure {
+            reason: cause.to_string(),
+            timeout: Some(cause),
+        }
+    }
+
+    fn stopped(context: &str, stop: Stop) -> Self {
+        match stop {
+            Stop::Timeout(cause) => Failure::timed_out(cause),
+            Stop::Io(e) => Failure::new(format!("{}: {}", context, e)),
+        }
+    }
+
+    /*
+     * What the client gets when this is the final failure: 504 if the
+     * backend ran out of time, 502 otherwise
+     */
+    fn response(&self) -> &'static str {
+        if self.timeout.is_some() {
+            GATEWAY_TIMEOUT
+        } else {
+            BAD_GATEWAY
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.reason)
+    }
+}
+
+/*
+ * Why one attempt at a backend did not complete
+ */
+enum AttemptError {
+    /*
+     * Nothing reached the client and the request may go to another backend
+     */
+    Retryable(Failure),
+    /*
+     * Nothing reached the client, but the backend may have acted on the
+     * request so it must not be repeated
+     */
+    Failed(Failure),
+    /*
+     * The client stalled while its body was being streamed
+     */
+    ClientTimeout,
+    /*
+     * The client connection broke, or the response broke off after it had
+     * started; either way the connection is done
+     */
+    Aborted(io::Error),
+}
+
+/*
+ * Makes one attempt at serving the request through `backend`, on an idle
+ * connection from its pool when there is one. The response head is awaited
+ * before the client sees anything, so a backend that drops a replayable
+ * request can still be swapped for another. Returns whether the client
+ * connection can be kept open, and records what happened in `entry`.
+ */
+async fn forward(
+    pool: &Pool,
+    backend: &Backend,
+    client: &mut BufReader<Stream>,
+    exchange: &Exchange,
+    entry: &mut Entry,
+) -> Result<bool, AttemptError> {
+    let started = Instant::now();
+    entry.connect_time = None;
+    entry.response_time = None;
+    let limits = &exchange.limits;
+    let request = &exchange.request;
+    let connection_pool = pool.connection_pool();
+    let tls = pool.tls();
+    let complete = |success, status| {
+        pool.completed(
+            backend,
+            &Outcome {
+                success,
+                status,
+                duration: started.elapsed(),
+            },
+        );
+    };
+    let fail = |observation, failure: Failure| {
+        pool.observe(backend, observation);
+        complete(false, None);
+        failure
+    };
+
+    let (opened, mut backend_reader, response, head, sent) = loop {
+        // A connection opened with a PROXY protocol header belongs to its
+        // client and is never shared
+        let idle = match exchange.proxy_header {
+            Some(_) => None,
+            None => backend.connections().checkout(&connection_pool),
+        };
+        let connection = match idle {
+            Some(connection) => connection,
+            None => {
+                let (connect_timeout, connect_cause) = limits
+                    .bound(exchange.connect_timeout, TimeoutCause::Connect)
+                    .map_err(|cause| AttemptError::Retryable(Failure::timed_out(cause)))?;
+                let connecting = Instant::now();
+                let opened = upstream::open(
+                    backend.address(),
+                    connect_timeout,
+                    exchange.proxy_header.as_deref(),
+                )
+                .await;
+                let connected = match opened {
+                    Ok(stream) => {
+                        upstream::secure(stream, tls.as_ref(), backend.address(), connect_timeout)
+                            .await
+                            .map_err(|e| ("TLS handshake failed", e))
+                    }
+                    Err(e) => Err(("connect failed", e)),
+                };
+                match connected {
+                    Ok(stream) => {
+                        entry.connect_time = Some(connecting.elapsed());
+                        pool.connected(backend);
+                        pool.observe(backend, Observation::Connected);
+                        Connection::new(stream)
+                    }
+                    Err((context, e)) => {
+                        let failure = if is_timeout(&e) {
+                            Failure::timed_out(connect_cause)
+                        } else {
+                            Failure::new(format!("{}: {}", context, e))
+                        };
+                        return Err(AttemptError::Retryable(fail(
+                            Observation::ConnectFailure,
+                            failure,
+                        )));
+                    }
+                }
+            }
+        };
+
+        let mut backend_reader = BufReader::new(connection.stream);
+        match send_request(client, &mut backend_reader, exchange).await {
+            Ok((response, head, sent)) => {
+                break (connection.opened, backend_reader, response, head, sent)
+            }
+            Err(SendError::Client(e)) => {
+                complete(false, None);
+                return Err(e);
+            }
+            // The backend closed the idle connection just as it was reused,
+            // which says nothing about the backend itself
+            Err(SendError::Backend { stale: true, .. }) if connection.reused => continue,
+            Err(SendError::Backend { failure, .. }) => {
+                let failure = fail(Observation::NoResponse, failure);
+                return Err(if exchange.body.is_some() {
+                    AttemptError::Retryable(failure)
+                } else {
+                    AttemptError::Failed(failure)
+                });
+            }
+        }
+    };
+    entry.response_time = Some(started.elapsed());
+    pool.observe(backend, Observation::Response(response.status));
+    let success = response.status < 500;
+
+    if response.switches_protocols(request) {
+        entry.status = Some(response.status);
+        return switch_protocols(client, backend, backend_reader, &head, exchange, entry)
+            .await
+            .inspect(|_| complete(success, Some(response.status)))
+            .inspect_err(|_| complete(false, Some(response.status)));
+    }
+
+    let Some(body_length) = response.body_length(request) else {
+        return Err(AttemptError::Failed(fail(
+            Observation::NoResponse,
+            Failure::new("malformed response framing".to_string()),
+        )));
+    };
+    let keep_alive = request.keep_alive()
+        && body_length != BodyLength::UntilClose
+        && !exchange.shutdown.is_requested();
+    let client_head = response.for_client(&head, keep_alive, request.version);
+    if let Err(stop) = limits
+        .within(
+            limits.idle,
+            TimeoutCause::Idle,
+            client.get_mut().write_all(&client_head),
+        )
+        .await
+    {
+        complete(success, Some(response.status));
+        return Err(AttemptError::Aborted(stop_error(stop)));
+    }
+    entry.status = Some(response.status);
+    entry.bytes = client_head.len() as u64;
+
+    let copied = copy_body(&mut backend_reader, client.get_mut(), body_length, limits).await;
+    complete(success && copied.is_ok(), Some(response.status));
+    let received = head.len() as u64 + copied.as_ref().map_or(0, |copied| *copied);
+    backend.stats().transferred(sent, received);
+    match copied {
+        Ok(copied) => {
+            entry.bytes += copied;
+            entry.termination = Some(Termination::Completed);
+            let reusable = response.keep_alive()
+                && body_length != BodyLength::UntilClose
+                && backend_reader.buffer().is_empty()
+                && exchange.proxy_header.is_none();
+            if reusable {
+                let connection = Connection {
+                    stream: backend_reader.into_inner(),
+                    opened,
+                    reused: true,
+                };
+                backend.connections().checkin(connection, &connection_pool);
+            }
+            Ok(keep_alive)
+        }
+        Err(BodyError::Read(stop)) => {
+            entry.termination = Some(match stop {
+                Stop::Timeout(cause) => Termination::timed_out(cause),
+                Stop::Io(_) => Termination::BackendAborted,
+            });
+            Err(AttemptError::Aborted(stop_error(stop)))
+        }
+        Err(BodyError::Write(stop)) => Err(AttemptError::Aborted(stop_error(stop))),
+    }
+}
+
+/*
+ * Why a request could not be sent or its response head not read
+ */
+enum SendError {
+    /*
+     * The client stalled or went away while its body was streamed
+     */
+    Client(AttemptError),
+    Backend {
+        failure: Failure,
+        /*
+         * Whether the backend went away before it could have seen the
+         * request, so that it can be sent again on a fresh connection
+         */
+        stale: bool,
+    },
+}
+
+/*
+ * Sends the request head and body to the backend and reads back the head
+ * of its final response, skipping informational ones; the client's
+ * `100-continue` was already answered. Also returns how many bytes were
+ * sent.
+ */
+async fn send_request(
+    client: &mut BufReader<Stream>,
+    backend_reader: &mut BufReader<Stream>,
+    exchange: &Exchange,
+) -> Result<(ResponseHead, Vec<u8>, u64), SendError> {
+    let limits = &exchange.limits;
+    let failed = |stop: Stop, stale: bool| SendError::Backend {
+        stale: stale && matches!(stop, Stop::Io(_)),
+        failure: Failure::stopped("sending request failed", stop),
+    };
+
+    let head_and_body = match &exchange.body {
+        Some(body) => [exchange.backend_head.as_slice(), body].concat(),
+        None => exchange.backend_head.clone(),
+    };
+    limits
+        .within(
+            limits.idle,
+            TimeoutCause::Idle,
+            backend_reader.get_mut().write_all(&head_and_body),
+        )
+        .await
+        .map_err(|stop| failed(stop, true))?;
+    let mut sent = head_and_body.len() as u64;
+    if exchange.body.is_none() && !exchange.request.is_upgrade() {
+        match copy_body(
+            client,
+            backend_reader.get_mut(),
+            exchange.body_length,
+            limits,
+        )
+        .await
+        {
+            Ok(copied) => sent += copied,
+            Err(BodyError::Read(Stop::Timeout(_))) => {
+                return Err(SendError::Client(AttemptError::ClientTimeout))
+            }
+            Err(BodyError::Read(Stop::Io(e))) => {
+                return Err(SendError::Client(AttemptError::Aborted(e)))
+            }
+            Err(BodyError::Write(stop)) => return Err(failed(stop, false)),
+        }
+    }
+
+    // Only a request that was buffered in full can be sent again once the
+    // backend read it and closed without a word
+    let replayable = exchange.body.is_some();
+    loop {
+        let mut head = Vec::new();
+        let mut response_limits = *limits;
+        let state = read_head(
+            backend_reader,
+            &mut head,
+            ResponseHead::state,
+            &mut response_limits,
+            (limits.first_byte, TimeoutCause::FirstByte),
+            None,
+        )
+        .await
+        .map_err(|stop| SendError::Backend {
+            stale: replayable && head.is_empty() && matches!(stop, Stop::Io(_)),
+            failure: Failure::stopped("reading response failed", stop),
+        })?;
+        let response = match state {
+            HeadState::Complete => ResponseHead::parse(&head),
+            _ if head.is_empty() => {
+                return Err(SendError::Backend {
+                    failure: Failure::new("closed without responding".to_string()),
+                    stale: replayable,
+                })
+            }
+            _ => None,
+        };
+        let Some(response) = response else {
+            return Err(SendError::Backend {
+                failure: Failure::new("malformed response".to_string()),
+                stale: false,
+            });
+        };
+        if !response.is_interim() {
+            return Ok((response, head, sent));
+        }
+    }
+}
+
+/*
+ * Hands the connection over to the raw relay after a `101 Switching
+ * Protocols` or an established CONNECT tunnel. The connection ends with it.
+ */
+async fn switch_protocols(
+    client: &mut BufReader<Stream>,
+    backend: &Backend,
+    backend_reader: BufReader<Stream>,
+    head: &[u8],
+    exchange: &Exchange,
+    entry: &mut Entry,
+) -> Result<bool, AttemptError> {
+    // Bytes either side sent early, after its head, are still buffered
+    let early_request = client.buffer().to_vec();
+    client.consume(early_request.len());
+    let response = [head, backend_reader.buffer()].concat();
+    let mut backend_stream = backend_reader.into_inner();
+    backend_stream
+        .write_all(&early_request)
+        .await
+        .map_err(AttemptError::Aborted)?;
+
+    let preamble = Preamble {
+        request_bytes: (exchange.backend_head.len() + early_request.len()) as u64,
+        response,
+    };
+    let limits = Limits {
+        deadline: None,
+        ..exchange.limits
+    };
+    let summary = relay(client.get_mut(), backend_stream, preamble, limits)
+        .await
+        .map_err(|error| AttemptError::Aborted(error.into()))?;
+    backend
+        .stats()
+        .transferred(summary.bytes_to_backend, summary.bytes_to_client);
+    entry.bytes = summary.bytes_to_client;
+    entry.termination = Some(match summary.timeout {
+        Some(cause) => Termination::timed_out(cause),
+        None => Termination::Upgraded,
+    });
+    Ok(false)
+}
+
+fn stop_error(stop: Stop) -> io::Error {
+    match stop {
+        Stop::Timeout(cause) => io::Error::new(io::ErrorKind::TimedOut, cause.to_string()),
+        Stop::Io(e) => e,
+    }
+}
+
+/*
+ * Creates a pool along with its health checker, which stops by itself once
+ * the pool is dropped. Before `run` there is no runtime to check on; it
+ * starts the checkers of the pools it finds.
+ */
+fn spawn_pool(runtime: Option<&Handle>, name: &str, config: &PoolConfig) -> Arc<Pool> {
+    let pool = Arc::new(Pool::new(name, config));
+    if let Some(runtime) = runtime {
+        health::spawn(runtime, &pool);
+    }
+    pool
+}
@@ -0,0 +1,1351 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer};
+use toml::Spanned;
+
+use crate::{strategy, tls};
+
+/*
+ * Top level layout of baalancer.toml
+ */
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(rename = "listener")]
+    pub listeners: Spanned<Vec<ListenerConfig>>,
+    #[serde(rename = "pool")]
+    pub pools: BTreeMap<String, PoolConfig>,
+    /*
+     * Tried in order for every request on every listener; the first match
+     * picks the pool
+     */
+    #[serde(rename = "route", default)]
+    pub routes: Vec<RouteConfig>,
+    /*
+     * Like `routes`, but for TLS passthrough listeners, which only get to
+     * see the server name
+     */
+    #[serde(rename = "sni_route", default)]
+    pub sni_routes: Vec<SniRouteConfig>,
+    #[serde(default)]
+    pub timeouts: TimeoutsConfig,
+    #[serde(default)]
+    pub reload: ReloadConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub forwarding: ForwardingConfig,
+    pub metrics: Option<MetricsConfig>,
+    pub admin: Option<AdminConfig>,
+    #[serde(default)]
+    pub access_log: AccessLogConfig,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: Spanned<Address>,
+    #[serde(default)]
+    pub mode: ListenerMode,
+    /*
+     * Pool for requests no route matches. Without one they get a 404.
+     */
+    pub pool: Option<Spanned<String>>,
+    /*
+     * Terminates TLS on this listener when present
+     */
+    pub tls: Option<Spanned<TlsConfig>>,
+    pub proxy_protocol: Option<ProxyProtocolConfig>,
+}
+
+/*
+ * Connections from `trusted_sources` start with a PROXY protocol header,
+ * v1 or v2, whose source address stands in for theirs. Others are taken as
+ * direct clients.
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyProtocolConfig {
+    pub trusted_sources: Vec<IpNetwork>,
+}
+
+impl ProxyProtocolConfig {
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_sources
+            .iter()
+            .any(|network| network.contains(ip))
+    }
+}
+
+/*
+ * PROXY protocol version sent to the backends of a pool
+ */
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyVersion {
+    /*
+     * The human-readable text header
+     */
+    V1,
+    /*
+     * The binary header
+     */
+    V2,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ListenerMode {
+    /*
+     * Parses requests and routes each one on its own
+     */
+    #[default]
+    Http,
+    /*
+     * Routes whole connections on the SNI of the TLS ClientHello and relays
+     * them to the backend still encrypted
+     */
+    TlsPassthrough,
+}
+
+/*
+ * Sends passthrough connections whose SNI matches `host` to `pool`
+ */
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniRouteConfig {
+    pub host: HostPattern,
+    pub pool: Spanned<String>,
+}
+
+/*
+ * Certificates and protocol settings of a TLS listener. The first
+ * certificate is also served to clients whose SNI matches no other, or who
+ * send none.
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    #[serde(rename = "certificate")]
+    pub certificates: Vec<CertificateConfig>,
+    #[serde(default = "default_tls_versions")]
+    pub versions: Vec<TlsVersion>,
+    /*
+     * Cipher suites by their IANA names, such as
+     * `TLS13_AES_128_GCM_SHA256`. Every suite the TLS stack supports when
+     * unset.
+     */
+    pub ciphers: Option<Vec<Spanned<String>>>,
+}
+
+/*
+ * A PEM certificate chain and its private key. Both files are watched and
+ * reloaded when they change.
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /*
+     * Server names this certificate is picked for
+     */
+    #[serde(default)]
+    pub hosts: Vec<HostPattern>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TlsVersion::Tls12 => "1.2",
+            TlsVersion::Tls13 => "1.3",
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for TlsVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match String::deserialize(deserializer)?.as_str() {
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            version => Err(de::Error::custom(format!(
+                "unsupported TLS version `{}`, expected \"1.2\" or \"1.3\"",
+                version
+            ))),
+        }
+    }
+}
+
+fn default_tls_versions() -> Vec<TlsVersion> {
+    vec![TlsVersion::Tls12, TlsVersion::Tls13]
+}
+
+/*
+ * Sends requests to `pool` when every condition given holds. A route with
+ * no conditions matches everything.
+ */
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub host: Option<HostPattern>,
+    /*
+     * Matches whole path segments, so `/api` covers `/api/users` but not
+     * `/apis`. The query string is not part of the path.
+     */
+    pub path_prefix: Option<Spanned<String>>,
+    #[serde(default, deserialize_with = "optional_regex")]
+    pub path_regex: Option<Regex>,
+    /*
+     * Any one of these methods
+     */
+    pub methods: Option<Vec<String>>,
+    /*
+     * Headers that must be present with exactly these values
+     */
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub pool: Spanned<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PoolConfig {
+    #[serde(default)]
+    pub algorithm: Algorithm,
+    #[serde(rename = "backend", default)]
+    pub backends: Vec<BackendConfig>,
+    pub health_check: Option<Spanned<HealthCheckConfig>>,
+    pub outlier_detection: Option<OutlierDetectionConfig>,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub connection_pool: ConnectionPoolConfig,
+    /*
+     * Speaks TLS to the backends when present. Passthrough connections are
+     * relayed as they are regardless.
+     */
+    pub tls: Option<Spanned<UpstreamTlsConfig>>,
+    /*
+     * Opens every backend connection with a PROXY protocol header naming
+     * the client. Such connections are not pooled, since each belongs to
+     * one client.
+     */
+    pub proxy_protocol: Option<ProxyVersion>,
+}
+
+/*
+ * TLS from the balancer to the backends of a pool. The files are read when
+ * the config is loaded, so a reload picks up rotated certificates.
+ */
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamTlsConfig {
+    /*
+     * PEM bundle of the CAs that backend certificates must chain to
+     */
+    pub ca_bundle: Option<PathBuf>,
+    /*
+     * Name sent as SNI and checked against the backend certificates,
+     * instead of the host of each backend's address
+     */
+    pub server_name: Option<String>,
+    /*
+     * Certificate chain and key presented to backends that ask for one
+     */
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    /*
+     * Accepts any backend certificate. For testing only.
+     */
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+    /*
+     * Built from the settings above by `Config::parse`
+     */
+    #[serde(skip)]
+    pub client_config: Option<Arc<rustls::ClientConfig>>,
+}
+
+/*
+ * Backend selection strategy of a pool. Anything other than the built-in
+ * names must have been registered through `strategy::register`.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Algorithm {
+    #[default]
+    RoundRobin,
+    WeightedRoundRobin,
+    LeastConnections,
+    WeightedLeastConnections,
+    Random,
+    Hash,
+    Custom(String),
+}
+
+impl Algorithm {
+    const BUILT_IN: [(&'static str, Algorithm); 6] = [
+        ("round_robin", Algorithm::RoundRobin),
+        ("weighted_round_robin", Algorithm::WeightedRoundRobin),
+        ("least_connections", Algorithm::LeastConnections),
+        (
+            "weighted_least_connections",
+            Algorithm::WeightedLeastConnections,
+        ),
+        ("random", Algorithm::Random),
+        ("hash", Algorithm::Hash),
+    ];
+
+    pub fn name(&self) -> &str {
+        match self {
+            Algorithm::Custom(name) => name,
+            built_in => Algorithm::BUILT_IN
+                .iter()
+                .find(|(_, algorithm)| algorithm == built_in)
+                .map_or("", |(name, _)| name),
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for Algorithm {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        if let Some((_, algorithm)) = Algorithm::BUILT_IN.iter().find(|(n, _)| *n == name) {
+            return Ok(algorithm.clone());
+        }
+        if strategy::is_registered(&name) {
+            return Ok(Algorithm::Custom(name));
+        }
+
+        let known: Vec<&str> = Algorithm::BUILT_IN.iter().map(|(n, _)| *n).collect();
+        Err(de::Error::custom(format!(
+            "unknown algorithm `{}`, expected one of {} or a registered strategy",
+            name,
+            known.join(", ")
+        )))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackendConfig {
+    pub address: Spanned<Address>,
+    #[serde(default = "default_weight", deserialize_with = "at_least_one")]
+    pub weight: u32,
+    /*
+     * Overrides individual fields of the pool's HTTP health check
+     */
+    pub health_check: Option<HttpCheckConfig>,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    #[serde(
+        rename = "connect_ms",
+        default = "default_connect_timeout",
+        deserialize_with = "millis"
+    )]
+    pub connect: Duration,
+    /*
+     * How long a backend may take to start answering once the client has
+     * stopped sending
+     */
+    #[serde(
+        rename = "first_byte_ms",
+        default = "default_first_byte_timeout",
+        deserialize_with = "millis"
+    )]
+    pub first_byte: Duration,
+    /*
+     * How long a connection may go without traffic in either direction,
+     * including while the client is still sending its request head
+     */
+    #[serde(
+        rename = "idle_ms",
+        default = "default_idle_timeout",
+        deserialize_with = "millis"
+    )]
+    pub idle: Duration,
+    /*
+     * Upper bound on each request from its first byte to the last byte of
+     * the response, off by default
+     */
+    #[serde(rename = "request_ms", default, deserialize_with = "optional_millis")]
+    pub request: Option<Duration>,
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        TimeoutsConfig {
+            connect: default_connect_timeout(),
+            first_byte: default_first_byte_timeout(),
+            idle: default_idle_timeout(),
+            request: None,
+        }
+    }
+}
+
+fn default_connect_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_first_byte_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_idle_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+/*
+ * SIGHUP always reloads; setting `watch_interval_ms` additionally polls the
+ * file's modification time
+ */
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReloadConfig {
+    #[serde(
+        rename = "watch_interval_ms",
+        default,
+        deserialize_with = "optional_millis"
+    )]
+    pub watch_interval: Option<Duration>,
+}
+
+/*
+ * On SIGTERM or SIGINT the listeners close and idle connections with them;
+ * connections still busy get `drain_timeout_ms` to finish before they are
+ * cut off
+ */
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShutdownConfig {
+    #[serde(
+        rename = "drain_timeout_ms",
+        default = "default_drain_timeout",
+        deserialize_with = "millis"
+    )]
+    pub drain_timeout: Duration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            drain_timeout: default_drain_timeout(),
+        }
+    }
+}
+
+fn default_drain_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+/*
+ * Controls the X-Forwarded-* and Forwarded headers added to every request
+ */
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardingConfig {
+    /*
+     * Clients whose own forwarding headers are kept and appended to. Anyone
+     * else could be lying about them, so theirs are replaced.
+     */
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNetwork>,
+}
+
+impl ForwardingConfig {
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|network| network.contains(ip))
+    }
+}
+
+/*
+ * One line per request, or per passthrough connection
+ */
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    pub format: LogFormat,
+    /*
+     * Appended to, and reopened on SIGUSR1 so it can be rotated. Standard
+     * output when unset.
+     */
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /*
+     * Apache's combined format followed by `key=value` fields for the
+     * backend, timings and termination
+     */
+    #[default]
+    Combined,
+    /*
+     * One JSON object per line
+     */
+    Json,
+}
+
+/*
+ * Serves the Prometheus metrics at `GET /metrics` on a listener of its own
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub address: Address,
+}
+
+/*
+ * Serves the admin API on a listener of its own, local only unless told
+ * otherwise. Every request must carry `Authorization: Bearer <token>`.
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    #[serde(default = "default_admin_address")]
+    pub address: Address,
+    pub token: Spanned<String>,
+}
+
+fn default_admin_address() -> Address {
+    Address("127.0.0.1:9901".to_string())
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    #[serde(rename = "interval_ms", deserialize_with = "millis")]
+    pub interval: Duration,
+    #[serde(rename = "timeout_ms", deserialize_with = "millis")]
+    pub timeout: Duration,
+    /*
+     * Consecutive passing checks before a down backend is used again
+     */
+    #[serde(default = "default_rise", deserialize_with = "at_least_one")]
+    pub rise: u32,
+    /*
+     * Consecutive failing checks before an up backend is taken out
+     */
+    #[serde(default = "default_fall", deserialize_with = "at_least_one")]
+    pub fall: u32,
+    /*
+     * Probe with an HTTP request instead of a bare TCP connect
+     */
+    pub http: Option<HttpCheckConfig>,
+}
+
+fn default_rise() -> u32 {
+    2
+}
+
+fn default_fall() -> u32 {
+    3
+}
+
+/*
+ * Retries move a request to another backend when connecting fails, or when
+ * the backend drops an idempotent request before answering
+ */
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    /*
+     * Retries after the first attempt, 0 disables retrying
+     */
+    #[serde(default = "default_retry_attempts")]
+    pub attempts: u32,
+    /*
+     * Connect timeout of each attempt, `timeouts.connect_ms` when unset
+     */
+    #[serde(
+        rename = "connect_timeout_ms",
+        default,
+        deserialize_with = "optional_millis"
+    )]
+    pub connect_timeout: Option<Duration>,
+    /*
+     * Retries in flight may not exceed this share of active requests...
+     */
+    #[serde(default = "default_budget_percent", deserialize_with = "percent")]
+    pub budget_percent: u8,
+    /*
+     * ...except that this many concurrent retries are always allowed
+     */
+    #[serde(default = "default_min_concurrent_retries")]
+    pub min_concurrent_retries: u32,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            attempts: default_retry_attempts(),
+            connect_timeout: None,
+            budget_percent: default_budget_percent(),
+            min_concurrent_retries: default_min_concurrent_retries(),
+        }
+    }
+}
+
+fn default_retry_attempts() -> u32 {
+    2
+}
+
+fn default_budget_percent() -> u8 {
+    20
+}
+
+fn default_min_concurrent_retries() -> u32 {
+    3
+}
+
+/*
+ * Keep-alive connections to each backend that are kept open between
+ * requests instead of connecting anew every time
+ */
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectionPoolConfig {
+    /*
+     * Idle connections kept per backend, 0 disables reuse
+     */
+    #[serde(default = "default_max_idle")]
+    pub max_idle: usize,
+    /*
+     * How long a connection may sit unused. Should be shorter than the
+     * backend's own keep-alive timeout, or reuse races the backend closing.
+     */
+    #[serde(
+        rename = "idle_timeout_ms",
+        default = "default_pool_idle_timeout",
+        deserialize_with = "millis"
+    )]
+    pub idle_timeout: Duration,
+    /*
+     * Age after which a connection is retired once its current response is
+     * done, off by default
+     */
+    #[serde(
+        rename = "max_lifetime_ms",
+        default,
+        deserialize_with = "optional_millis"
+    )]
+    pub max_lifetime: Option<Duration>,
+}
+
+impl Default for ConnectionPoolConfig {
+    fn default() -> Self {
+        ConnectionPoolConfig {
+            max_idle: default_max_idle(),
+            idle_timeout: default_pool_idle_timeout(),
+            max_lifetime: None,
+        }
+    }
+}
+
+fn default_max_idle() -> usize {
+    16
+}
+
+fn default_pool_idle_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+/*
+ * Passive health checking: backends failing live traffic are ejected from
+ * the pool for `base_ejection_ms`, doubling with every repeat ejection up to
+ * `max_ejection_ms`
+ */
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutlierDetectionConfig {
+    #[serde(default = "default_consecutive_5xx", deserialize_with = "at_least_one")]
+    pub consecutive_5xx: u32,
+    #[serde(
+        default = "default_consecutive_connect_failures",
+        deserialize_with = "at_least_one"
+    )]
+    pub consecutive_connect_failures: u32,
+    #[serde(
+        rename = "base_ejection_ms",
+        default = "default_base_ejection",
+        deserialize_with = "millis"
+    )]
+    pub base_ejection: Duration,
+    #[serde(
+        rename = "max_ejection_ms",
+        default = "default_max_ejection",
+        deserialize_with = "millis"
+    )]
+    pub max_ejection: Duration,
+    /*
+     * Upper bound on the share of the pool ejected at once. The last
+     * remaining backend is never ejected regardless.
+     */
+    #[serde(default = "default_max_ejection_percent", deserialize_with = "percent")]
+    pub max_ejection_percent: u8,
+}
+
+fn default_consecutive_5xx() -> u32 {
+    5
+}
+
+fn default_consecutive_connect_failures() -> u32 {
+    3
+}
+
+fn default_base_ejection() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_max_ejection() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_max_ejection_percent() -> u8 {
+    50
+}
+
+/*
+ * Every field is optional so that a backend can override just the ones it
+ * needs; `merge` layers a backend's table over the pool's
+ */
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpCheckConfig {
+    pub method: Option<String>,
+    pub path: Option<String>,
+    /*
+     * Host header to send, the backend address when unset
+     */
+    pub host: Option<String>,
+    pub expected_status: Option<Vec<StatusRange>>,
+    pub body_contains: Option<String>,
+    #[serde(default, deserialize_with = "optional_regex")]
+    pub body_regex: Option<Regex>,
+}
+
+impl HttpCheckConfig {
+    pub fn merge(&self, overrides: &HttpCheckConfig) -> HttpCheckConfig {
+        HttpCheckConfig {
+            method: overrides.method.clone().or_else(|| self.method.clone()),
+            path: overrides.path.clone().or_else(|| self.path.clone()),
+            host: overrides.host.clone().or_else(|| self.host.clone()),
+            expected_status: overrides
+                .expected_status
+                .clone()
+                .or_else(|| self.expected_status.clone()),
+            body_contains: overrides
+                .body_contains
+                .clone()
+                .or_else(|| self.body_contains.clone()),
+            body_regex: overrides
+                .body_regex
+                .clone()
+                .or_else(|| self.body_regex.clone()),
+        }
+    }
+
+    pub fn method(&self) -> &str {
+        self.method.as_deref().unwrap_or("GET")
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.as_deref().unwrap_or("/")
+    }
+
+    /*
+     * Any 2xx or 3xx passes unless `expected_status` says otherwise
+     */
+    pub fn status_matches(&self, status: u16) -> bool {
+        match &self.expected_status {
+            Some(ranges) => ranges.iter().any(|range| range.contains(status)),
+            None => (200..400).contains(&status),
+        }
+    }
+}
+
+/*
+ * Either a single code such as `200` or an inclusive range such as "200-299"
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StatusRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl StatusRange {
+    pub fn contains(&self, status: u16) -> bool {
+        (self.start..=self.end).contains(&status)
+    }
+}
+
+impl fmt::Display for StatusRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for StatusRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct StatusRangeVisitor;
+
+        impl de::Visitor<'_> for StatusRangeVisitor {
+            type Value = StatusRange;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a status code or a \"start-end\" range")
+            }
+
+            fn visit_i64<E: de::Error>(self, code: i64) -> Result<StatusRange, E> {
+                let code = status_code(&code.to_string())?;
+                Ok(StatusRange {
+                    start: code,
+                    end: code,
+                })
+            }
+
+            fn visit_str<E: de::Error>(self, range: &str) -> Result<StatusRange, E> {
+                let (start, end) = range.split_once('-').unwrap_or((range, range));
+                let range = StatusRange {
+                    start: status_code(start.trim())?,
+                    end: status_code(end.trim())?,
+                };
+                if range.start > range.end {
+                    return Err(E::custom(format!("status range `{}` is reversed", range)));
+                }
+                Ok(range)
+            }
+        }
+
+        deserializer.deserialize_any(StatusRangeVisitor)
+    }
+}
+
+fn status_code<E: de::Error>(code: &str) -> Result<u16, E> {
+    match code.parse::<u16>() {
+        Ok(code) if (100..=599).contains(&code) => Ok(code),
+        _ => Err(E::custom(format!("`{}` is not an HTTP status code", code))),
+    }
+}
+
+/*
+ * A `host:port` pair, checked when the config is parsed so a typo is
+ * reported against its line rather than on the first connection attempt
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Address(String);
+
+impl Address {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("address `{}` must be in host:port form", s))?;
+        if host.is_empty() {
+            return Err(format!("address `{}` is missing a host", s));
+        }
+        match port.parse::<u16>() {
+            Ok(port) if port != 0 => Ok(Address(s.to_string())),
+            _ => Err(format!("address `{}` has an invalid port", s)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/*
+ * A host name as it appears in the Host header, without the port. A leading
+ * `*.` matches any subdomain but not the domain itself.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostPattern(String);
+
+impl HostPattern {
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        match self.0.strip_prefix('*') {
+            Some(suffix) => host.len() > suffix.len() && host.ends_with(suffix),
+            None => host == self.0,
+        }
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for HostPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.strip_prefix("*.").unwrap_or(s);
+        if name.is_empty() || name.contains(['*', ':', '/', ' ']) {
+            return Err(format!(
+                "host `{}` must be a host name, optionally starting with `*.`",
+                s
+            ));
+        }
+        Ok(HostPattern(s.to_ascii_lowercase()))
+    }
+}
+
+impl<'de> Deserialize<'de> for HostPattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/*
+ * An address range in CIDR notation such as `10.0.0.0/8`; a bare address
+ * stands for itself alone
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .map_err(|_| format!("`{}` is not an IP address or CIDR range", s))?;
+        let address = address.to_canonical();
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix.map(str::parse::<u8>) {
+            None => max,
+            Some(Ok(prefix)) if prefix <= max => prefix,
+            Some(_) => return Err(format!("`{}` has an invalid prefix length", s)),
+        };
+        Ok(IpNetwork { address, prefix })
+    }
+}
+
+impl<'de> Deserialize<'de> for IpNetwork {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/*
+ * Longest duration any setting takes, one year. Deadlines are computed as
+ * `Instant::now() + duration`, which would overflow for values near
+ * `u64::MAX` milliseconds.
+ */
+const MAX_MILLIS: u64 = 365 * 24 * 60 * 60 * 1000;
+
+fn millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    match u64::deserialize(deserializer)? {
+        0 => Err(de::Error::custom("duration must be greater than zero")),
+        ms if ms > MAX_MILLIS => Err(de::Error::custom(format!(
+            "duration must be at most {} ms (one year)",
+            MAX_MILLIS
+        ))),
+        ms => Ok(Duration::from_millis(ms)),
+    }
+}
+
+fn at_least_one<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    match u32::deserialize(deserializer)? {
+        0 => Err(de::Error::custom("value must be at least 1")),
+        value => Ok(value),
+    }
+}
+
+fn percent<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    match u8::deserialize(deserializer)? {
+        percent @ 0..=100 => Ok(percent),
+        _ => Err(de::Error::custom("percentage must be between 0 and 100")),
+    }
+}
+
+fn optional_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Regex>, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map(Some).map_err(de::Error::custom)
+}
+
+fn optional_millis<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    millis(deserializer).map(Some)
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        source: Box<toml::de::Error>,
+    },
+    Invalid {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    /*
+     * Valid on its own but cannot be applied to the running balancer
+     */
+    Rejected {
+        path: PathBuf,
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "failed to read {}: {}", path.display(), source)
+            }
+            ConfigError::Parse { path, source } => {
+                write!(f, "failed to parse {}: {}", path.display(), source)
+            }
+            ConfigError::Invalid {
+                path,
+                line,
+                column,
+                message,
+            } => write!(f, "{}:{}:{}: {}", path.display(), line, column, message),
+            ConfigError::Rejected { path, message } => {
+                write!(f, "{}: {}", path.display(), message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let source = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Config::parse(path, &source)
+    }
+
+    /*
+     * Parses and validates the given TOML. `path` is only used to label errors.
+     */
+    pub fn parse(path: &Path, source: &str) -> Result<Config, ConfigError> {
+        let mut config: Config = toml::from_str(source).map_err(|e| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source: Box::new(e),
+        })?;
+
+        config
+            .validate()
+            .and_then(|_| config.load_upstream_tls())
+            .map_err(|(span, message)| {
+                let (line, column) = line_column(source, span.start);
+                ConfigError::Invalid {
+                    path: path.to_path_buf(),
+                    line,
+                    column,
+                    message,
+                }
+            })?;
+
+        Ok(config)
+    }
+
+    /*
+     * Reads the CA bundles and client certificates of every pool with
+     * upstream TLS
+     */
+    fn load_upstream_tls(&mut self) -> Result<(), (std::ops::Range<usize>, String)> {
+        for (name, pool) in &mut self.pools {
+            let Some(tls) = &mut pool.tls else {
+                continue;
+            };
+            let span = tls.span();
+            let client_config = tls::client_config(tls.get_ref())
+                .map_err(|e| (span, format!("TLS for pool `{}`: {}", name, e)))?;
+            tls.get_mut().client_config = Some(Arc::new(client_config));
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), (std::ops::Range<usize>, String)> {
+        if self.listeners.get_ref().is_empty() {
+            return Err((
+                self.listeners.span(),
+                "at least one [[listener]] is required".to_string(),
+            ));
+        }
+
+        let pools = self
+            .listeners
+            .get_ref()
+            .iter()
+            .filter_map(|listener| listener.pool.as_ref())
+            .chain(self.routes.iter().map(|route| &route.pool))
+            .chain(self.sni_routes.iter().map(|route| &route.pool));
+        for pool in pools {
+            if !self.pools.contains_key(pool.get_ref()) {
+                return Err((pool.span(), format!("unknown pool `{}`", pool.get_ref())));
+            }
+        }
+        for listener in self.listeners.get_ref() {
+            let (routes, table) = match listener.mode {
+                ListenerMode::Http => (self.routes.len(), "[[route]]"),
+                ListenerMode::TlsPassthrough => (self.sni_routes.len(), "[[sni_route]]"),
+            };
+            if listener.pool.is_none() && routes == 0 {
+                return Err((
+                    listener.address.span(),
+                    format!(
+                        "listener {} has no pool and there is no {} to pick one",
+                        listener.address.get_ref(),
+                        table
+                    ),
+                ));
+            }
+            if let Some(tls) = &listener.tls {
+                if listener.mode == ListenerMode::TlsPassthrough {
+                    return Err((
+                        tls.span(),
+                        "a tls_passthrough listener cannot also terminate TLS".to_string(),
+                    ));
+                }
+                validate_tls(tls)?;
+            }
+        }
+
+        for route in &self.routes {
+            if let Some(prefix) = &route.path_prefix {
+                if !prefix.get_ref().starts_with('/') {
+                    return Err((prefix.span(), "path_prefix must start with `/`".to_string()));
+                }
+            }
+        }
+
+        for (name, pool) in &self.pools {
+            let mut seen = HashSet::new();
+            for backend in &pool.backends {
+                if !seen.insert(backend.address.get_ref()) {
+                    return Err((
+                        backend.address.span(),
+                        format!(
+                            "backend `{}` is listed twice in pool `{}`",
+                            backend.address.get_ref(),
+                            name
+                        ),
+                    ));
+                }
+            }
+
+            if let Some(tls) = &pool.tls {
+                validate_upstream_tls(tls)?;
+            }
+
+            if let Some(health_check) = &pool.health_check {
+                let check = health_check.get_ref();
+                if check.timeout >= check.interval {
+                    return Err((
+                        health_check.span(),
+                        "health check timeout_ms must be shorter than interval_ms".to_string(),
+                    ));
+                }
+            }
+        }
+
+        if let Some(admin) = &self.admin {
+            if admin.token.get_ref().trim().is_empty() {
+                return Err((admin.token.span(), "the admin token is empty".to_string()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn validate_upstream_tls(
+    tls: &Spanned<UpstreamTlsConfig>,
+) -> Result<(), (std::ops::Range<usize>, String)> {
+    let config = tls.get_ref();
+    let message = if config.client_cert.is_some() != config.client_key.is_some() {
+        "client_cert and client_key must be given together"
+    } else if config.ca_bundle.is_none() && !config.insecure_skip_verify {
+        "upstream TLS needs a ca_bundle, or insecure_skip_verify for testing"
+    } else if config.ca_bundle.is_some() && config.insecure_skip_verify {
+        "ca_bundle has no effect with insecure_skip_verify"
+    } else if config
+        .server_name
+        .as_deref()
+        .is_some_and(|name| rustls::pki_types::ServerName::try_from(name).is_err())
+    {
+        "server_name is not a valid DNS name or IP address"
+    } else {
+        return Ok(());
+    };
+    Err((tls.span(), message.to_string()))
+}
+
+fn validate_tls(tls: &Spanned<TlsConfig>) -> Result<(), (std::ops::Range<usize>, String)> {
+    let config = tls.get_ref();
+    if config.certificates.is_empty() {
+        return Err((
+            tls.span(),
+            "a TLS listener needs at least one [[listener.tls.certificate]]".to_string(),
+        ));
+    }
+    if config.versions.is_empty() {
+        return Err((tls.span(), "no TLS versions enabled".to_string()));
+    }
+    let Some(ciphers) = &config.ciphers else {
+        return Ok(());
+    };
+    for cipher in ciphers {
+        if tls::cipher_suite(cipher.get_ref()).is_none() {
+            return Err((
+                cipher.span(),
+                format!("unknown cipher suite `{}`", cipher.get_ref()),
+            ));
+        }
+    }
+    // Each enabled version needs a suite of its own to negotiate
+    for version in &config.versions {
+        let usable = ciphers.iter().any(|cipher| {
+            tls::cipher_suite(cipher.get_ref())
+                .is_some_and(|suite| tls::suite_version(suite) == *version)
+        });
+        if !usable {
+            return Err((
+                tls.span(),
+                format!("no cipher suite listed for TLS {}", version),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Converts a byte offset into a 1-based line and column
+ */
+fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before
+        .rfind('\n')
+        .map_or(before.len(), |i| before.len() - i - 1)
+        + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(source: &str) -> (usize, usize) {
+        match Config::parse(Path::new("test.toml"), source) {
+            Err(ConfigError::Invalid { line, column, .. }) => (line, column),
+            other => panic!("expected a positioned error, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn empty_listener_list_points_at_it() {
+        let source = "listener = []\n\n[[pool.a.backend]]\naddress = \"127.0.0.1:8081\"\n";
+        assert_eq!(position(source), (1, 12));
+    }
+
+    #[test]
+    fn listener_without_pool_points_at_its_address() {
+        let source = "[[pool.a.backend]]\naddress = \"127.0.0.1:8081\"\n\n[[listener]]\naddress = \"127.0.0.1:9700\"\n";
+        assert_eq!(position(source), (5, 11));
+    }
+}
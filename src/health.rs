@@ -0,0 +1,284 @@
+use std::{
+    io,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    runtime::Handle,
+    task::JoinSet,
+    time,
+};
+
+use crate::{
+    backend::{Backend, Pool},
+    config::{HealthCheckConfig, HttpCheckConfig},
+    http::{self, BodyLength, RequestHead, ResponseHead},
+    proxy,
+    relay::{chunk_size, is_timeout},
+    upstream,
+};
+
+/*
+ * Responses are only inspected up to this size; body matches beyond it fail
+ */
+const MAX_RESPONSE: usize = 64 * 1024;
+
+/*
+ * How often a pool without a [health_check] table looks again, in case a
+ * reload adds one
+ */
+const DISABLED_POLL: Duration = Duration::from_secs(1);
+
+/*
+ * Starts the background checker for `pool` on `runtime`. It only holds a
+ * weak reference between rounds, so it exits once the pool is removed by a
+ * reload.
+ */
+pub fn spawn(runtime: &Handle, pool: &Arc<Pool>) {
+    runtime.spawn(run(Arc::downgrade(pool)));
+}
+
+async fn run(pool: Weak<Pool>) {
+    loop {
+        let pause = match pool.upgrade() {
+            Some(pool) => match pool.health_check() {
+                Some(config) => {
+                    check_pool(&pool, &config).await;
+                    config.interval
+                }
+                None => DISABLED_POLL,
+            },
+            None => return,
+        };
+        time::sleep(pause).await;
+    }
+}
+
+/*
+ * Probes every backend of the pool concurrently so one slow backend cannot
+ * delay the verdict on the others
+ */
+async fn check_pool(pool: &Arc<Pool>, config: &HealthCheckConfig) {
+    let mut probes = JoinSet::new();
+    for backend in pool.backends() {
+        let pool = Arc::clone(pool);
+        let config = config.clone();
+        probes.spawn(async move { check_backend(&pool, &backend, &config).await });
+    }
+    while probes.join_next().await.is_some() {}
+}
+
+async fn check_backend(pool: &Pool, backend: &Backend, config: &HealthCheckConfig) {
+    let http = match (&config.http, backend.http_check()) {
+        (Some(pool_check), Some(overrides)) => Some(pool_check.merge(&overrides)),
+        (pool_check, overrides) => pool_check.clone().or(overrides),
+    };
+    let probe = async {
+        match &http {
+            Some(http) => probe_http(pool, backend, http, config.timeout).await,
+            None => upstream::connect(backend.address(), config.timeout)
+                .await
+                .map(|_| ())
+                .map_err(|e| format!("connect failed: {}", e)),
+        }
+    };
+    let result = match time::timeout(config.timeout, probe).await {
+        Ok(result) => result,
+        Err(_) => Err("timed out".to_string()),
+    };
+    let reason = result.as_ref().err().cloned();
+
+    match backend.record_check(result, config.rise, config.fall) {
+        Some(true) => eprintln!(
+            "Backend {} in pool {} is up after {} passing checks",
+            backend.address(),
+            pool.name(),
+            config.rise
+        ),
+        Some(false) => eprintln!(
+            "Backend {} in pool {} is down: {}",
+            backend.address(),
+            pool.name(),
+            reason.unwrap_or_default()
+        ),
+        None => {}
+    }
+}
+
+/*
+ * Sends one request with `Connection: close` and judges the status line and
+ * body, over TLS and after a PROXY protocol header when the pool uses them
+ * with its backends. The caller bounds the whole exchange by the check's
+ * timeout.
+ */
+async fn probe_http(
+    pool: &Pool,
+    backend: &Backend,
+    check: &HttpCheckConfig,
+    timeout: Duration,
+) -> Result<(), String> {
+    let proxy_header = pool.proxy_protocol().map(proxy::local_header);
+    let stream = upstream::open(backend.address(), timeout, proxy_header.as_deref())
+        .await
+        .map_err(|e| format!("connect failed: {}", e))?;
+    let mut stream = upstream::secure(stream, pool.tls().as_ref(), backend.address(), timeout)
+        .await
+        .map_err(|e| format!("TLS handshake failed: {}", e))?;
+
+    let host = check.host.as_deref().unwrap_or(backend.address());
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: baalancer-health-check\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        check.method(),
+        check.path(),
+        host
+    );
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| format!("sending request failed: {}", e))?;
+
+    let mut response = Vec::new();
+    let mut buffer = [0; 4096];
+    while response.len() < MAX_RESPONSE {
+        match stream.read(&mut buffer).await {
+            Ok(0) => break,
+            // Plenty of servers close without a TLS close_notify
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Ok(n) => response.extend_from_slice(&buffer[..n]),
+            Err(e) if is_timeout(&e) => return Err("timed out reading response".to_string()),
+            Err(e) => return Err(format!("reading response failed: {}", e)),
+        }
+    }
+
+    judge(check, &request, &response)
+}
+
+/*
+ * Holds the response, as far as it was read, against the check
+ */
+fn judge(check: &HttpCheckConfig, request: &str, response: &[u8]) -> Result<(), String> {
+    let status = http::parse_status(response).ok_or("malformed response status line")?;
+    if !check.status_matches(status) {
+        return Err(format!("unexpected status {}", status));
+    }
+    if check.body_contains.is_none() && check.body_regex.is_none() {
+        return Ok(());
+    }
+
+    let body = response_body(request, response)?;
+    let body = String::from_utf8_lossy(&body);
+    if let Some(needle) = &check.body_contains {
+        if !body.contains(needle.as_str()) {
+            return Err(format!("body does not contain `{}`", needle));
+        }
+    }
+    if let Some(pattern) = &check.body_regex {
+        if !pattern.is_match(&body) {
+            return Err(format!("body does not match /{}/", pattern));
+        }
+    }
+
+    Ok(())
+}
+
+/*
+ * The body of `response` as the backend meant it, without chunked framing.
+ * Whatever MAX_RESPONSE cut off is missing from the end.
+ */
+fn response_body(request: &str, response: &[u8]) -> Result<Vec<u8>, String> {
+    let request = RequestHead::parse(request.as_bytes()).ok_or("malformed probe request")?;
+    let head = ResponseHead::parse(response).ok_or("malformed response head")?;
+    let body = &response[head.length..];
+    match head
+        .body_length(&request)
+        .ok_or("malformed response framing")?
+    {
+        BodyLength::Fixed(length) => Ok(body[..body.len().min(length as usize)].to_vec()),
+        BodyLength::UntilClose => Ok(body.to_vec()),
+        BodyLength::Chunked => dechunk(body),
+    }
+}
+
+/*
+ * Joins the chunks of a chunked body, stopping at the last chunk or where
+ * the body was cut off
+ */
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decoded = Vec::new();
+    while let Some(end) = body.iter().position(|&byte| byte == b'\n') {
+        let line = &body[..end];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let size = chunk_size(line).ok_or("malformed chunk size")?;
+        body = &body[end + 1..];
+        if size == 0 {
+            break;
+        }
+        let (chunk, rest) = body.split_at(body.len().min(size as usize));
+        decoded.extend_from_slice(chunk);
+        body = rest
+            .strip_prefix(b"\r\n")
+            .or_else(|| rest.strip_prefix(b"\n"))
+            .unwrap_or(rest);
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST: &str = "GET /health HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n";
+
+    fn check(source: &str) -> HttpCheckConfig {
+        toml::from_str(source).unwrap()
+    }
+
+    #[test]
+    fn matches_a_content_length_body() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nstatus: upTRAILING";
+        assert_eq!(response_body(REQUEST, response).unwrap(), b"status: u");
+        assert!(judge(&check("body_regex = \"^status: u$\""), REQUEST, response).is_ok());
+        assert!(judge(&check("body_contains = \"up\""), REQUEST, response).is_err());
+    }
+
+    #[test]
+    fn matches_a_chunked_body_without_its_framing() {
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            4;ext=1\r\nstat\r\n6\r\nus: up\r\n0\r\nTrailer: x\r\n\r\n";
+        assert_eq!(response_body(REQUEST, response).unwrap(), b"status: up");
+        assert!(judge(&check("body_contains = \"status: up\""), REQUEST, response).is_ok());
+        assert!(judge(&check("body_regex = \"^status: up$\""), REQUEST, response).is_ok());
+    }
+
+    #[test]
+    fn keeps_what_arrived_of_a_cut_off_chunked_body() {
+        let response =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n10\r\nwor";
+        assert_eq!(response_body(REQUEST, response).unwrap(), b"hellowor");
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        let response =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n+5\r\nhello\r\n0\r\n\r\n";
+        assert!(judge(&check("body_contains = \"hello\""), REQUEST, response).is_err());
+    }
+
+    #[test]
+    fn reads_a_body_running_until_close() {
+        let response = b"HTTP/1.1 200 OK\r\n\r\nall of it";
+        assert_eq!(response_body(REQUEST, response).unwrap(), b"all of it");
+    }
+
+    #[test]
+    fn judges_the_status_before_the_body() {
+        let response = b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 2\r\n\r\nup";
+        assert_eq!(
+            judge(&check("body_contains = \"up\""), REQUEST, response),
+            Err("unexpected status 503".to_string())
+        );
+        assert!(judge(&check(""), REQUEST, b"garbage").is_err());
+    }
+}
@@ -0,0 +1,727 @@
+use std::net::IpAddr;
+
+/*
+ * Extracts the code from a status line such as `HTTP/1.1 200 OK`. The line
+ * may be cut short after the code.
+ */
+pub fn parse_status(response: &[u8]) -> Option<u16> {
+    let line_end = response
+        .windows(2)
+        .position(|window| window == b"\r\n")
+        .unwrap_or(response.len());
+    let line = std::str::from_utf8(&response[..line_end]).ok()?;
+    let mut parts = line.split_whitespace();
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+/*
+ * Upper bound on a request or response head; anything longer is rejected
+ */
+pub const MAX_HEAD: usize = 16 * 1024;
+const MAX_HEADERS: usize = 64;
+
+/*
+ * Headers that describe a single connection rather than the message, and so
+ * are not passed on by a proxy (RFC 9110 section 7.6.1)
+ */
+const HOP_BY_HOP: [&str; 5] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "TE",
+    "Upgrade",
+];
+
+const FORWARDING_HEADERS: [&str; 4] = [
+    "X-Forwarded-For",
+    "X-Forwarded-Proto",
+    "X-Forwarded-Host",
+    "Forwarded",
+];
+
+/*
+ * Parsed request line and headers of an HTTP/1.x request
+ */
+#[derive(Clone, Debug)]
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    pub version: u8,
+    pub headers: Vec<(String, String)>,
+    /*
+     * Bytes taken up by the head including the blank line
+     */
+    pub length: usize,
+}
+
+/*
+ * Parsed status line and headers of an HTTP/1.x response
+ */
+#[derive(Clone, Debug)]
+pub struct ResponseHead {
+    pub version: u8,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub length: usize,
+}
+
+/*
+ * How far a buffer gets towards a complete head
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeadState {
+    Complete,
+    Partial,
+    Invalid,
+}
+
+/*
+ * What the backend gets told about where a request came from
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct Forwarding<'a> {
+    pub client: IpAddr,
+    /*
+     * Scheme the client used to reach the balancer
+     */
+    pub proto: &'a str,
+    /*
+     * Whether the client is a proxy whose own forwarding headers can be
+     * believed and appended to
+     */
+    pub trusted: bool,
+}
+
+/*
+ * How the end of a message body is found
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyLength {
+    Fixed(u64),
+    Chunked,
+    /*
+     * Only for responses: the body runs until the backend closes
+     */
+    UntilClose,
+}
+
+impl RequestHead {
+    /*
+     * Parses the head at the start of `buffer`. Returns None while it is
+     * incomplete or when it is not valid HTTP.
+     */
+    pub fn parse(buffer: &[u8]) -> Option<RequestHead> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        let length = match request.parse(buffer) {
+            Ok(httparse::Status::Complete(length)) => length,
+            _ => return None,
+        };
+
+        Some(RequestHead {
+            method: request.method?.to_string(),
+            path: request.path?.to_string(),
+            version: request.version?,
+            headers: owned_headers(request.headers),
+            length,
+        })
+    }
+
+    pub fn state(buffer: &[u8]) -> HeadState {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        head_state(httparse::Request::new(&mut headers).parse(buffer))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /*
+     * Methods RFC 9110 allows a client to repeat without changing the outcome
+     */
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self.method.as_str(),
+            "GET" | "HEAD" | "OPTIONS" | "TRACE" | "PUT" | "DELETE"
+        )
+    }
+
+    /*
+     * How the request body is framed, or None when the framing headers are
+     * contradictory or malformed and the request has to be refused. A
+     * request without framing headers has no body.
+     */
+    pub fn body_length(&self) -> Option<BodyLength> {
+        match body_framing(&self.headers)? {
+            BodyLength::UntilClose if self.header("Transfer-Encoding").is_some() => None,
+            BodyLength::UntilClose => Some(BodyLength::Fixed(0)),
+            length => Some(length),
+        }
+    }
+
+    /*
+     * Whether the client wants the connection kept open after this request
+     */
+    pub fn keep_alive(&self) -> bool {
+        keeps_alive(self.version, &self.headers)
+    }
+
+    /*
+     * Whether the client asks to switch to another protocol on this
+     * connection, such as a WebSocket handshake or a CONNECT tunnel
+     */
+    pub fn is_upgrade(&self) -> bool {
+        self.method == "CONNECT"
+            || (self.header("Upgrade").is_some()
+                && connection_tokens(&self.headers)
+                    .iter()
+                    .any(|token| token == "upgrade"))
+    }
+
+    /*
+     * Whether the client waits for `100 Continue` before sending its body
+     */
+    pub fn expects_continue(&self) -> bool {
+        self.header("Expect")
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("100-continue"))
+    }
+
+    /*
+     * Every value of a header that may be repeated, joined into one list
+     */
+    fn header_list(&self, name: &str) -> Option<String> {
+        let values: Vec<&str> = self
+            .headers
+            .iter()
+            .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+            .filter(|value| !value.is_empty())
+            .collect();
+        (!values.is_empty()).then(|| values.join(", "))
+    }
+
+    /*
+     * Rewrites `head`, the raw bytes of this head, into the one sent to the
+     * backend. It carries X-Forwarded-For, X-Forwarded-Proto,
+     * X-Forwarded-Host and an RFC 7239 Forwarded header for `forwarding`,
+     * drops hop-by-hop headers and asks the backend to keep the connection
+     * open for reuse or to close it after responding, unless the client is
+     * upgrading it. An `Expect: 100-continue` is dropped as the balancer
+     * answers it itself.
+     */
+    pub fn for_backend(&self, head: &[u8], forwarding: &Forwarding, keep_alive: bool) -> Vec<u8> {
+        let client = forwarding.client.to_canonical();
+        let host = self.header("Host").map(str::trim);
+        let trusted = |name| {
+            if forwarding.trusted {
+                self.header_list(name)
+            } else {
+                None
+            }
+        };
+
+        let forwarded_for = match trusted("X-Forwarded-For") {
+            Some(list) => format!("{}, {}", list, client),
+            None => client.to_string(),
+        };
+        let proto = trusted("X-Forwarded-Proto").unwrap_or_else(|| forwarding.proto.to_string());
+        let forwarded_host = trusted("X-Forwarded-Host").or(host.map(str::to_string));
+
+        let node = match client {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+        };
+        let mut element = format!("for={}", node);
+        if let Some(host) = host {
+            element.push_str(&format!(";host={}", quote(host)));
+        }
+        element.push_str(&format!(";proto={}", quote(forwarding.proto)));
+        let forwarded = match trusted("Forwarded") {
+            Some(list) => format!("{}, {}", list, element),
+            None => element,
+        };
+
+        let upgrade = self.is_upgrade();
+        let listed = connection_tokens(&self.headers);
+        let chunked = self.body_length() == Some(BodyLength::Chunked);
+        let dropped = |name: &str| {
+            FORWARDING_HEADERS
+                .iter()
+                .any(|header| header.eq_ignore_ascii_case(name))
+                || (upgrade && name.eq_ignore_ascii_case("Connection"))
+                || (!upgrade && is_hop_by_hop(name, &listed))
+                || (chunked && name.eq_ignore_ascii_case("Content-Length"))
+                || (name.eq_ignore_ascii_case("Expect") && self.expects_continue())
+        };
+
+        let mut added = vec![
+            ("X-Forwarded-For", forwarded_for),
+            ("X-Forwarded-Proto", proto),
+        ];
+        if let Some(forwarded_host) = forwarded_host {
+            added.push(("X-Forwarded-Host", forwarded_host));
+        }
+        added.push(("Forwarded", forwarded));
+        let connection = if upgrade {
+            "upgrade"
+        } else if keep_alive {
+            "keep-alive"
+        } else {
+            "close"
+        };
+        added.push(("Connection", connection.to_string()));
+
+        rewrite(head, None, dropped, &added)
+    }
+}
+
+impl ResponseHead {
+    pub fn parse(buffer: &[u8]) -> Option<ResponseHead> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut response = httparse::Response::new(&mut headers);
+        let length = match response.parse(buffer) {
+            Ok(httparse::Status::Complete(length)) => length,
+            _ => return None,
+        };
+
+        Some(ResponseHead {
+            version: response.version?,
+            status: response.code?,
+            headers: owned_headers(response.headers),
+            length,
+        })
+    }
+
+    pub fn state(buffer: &[u8]) -> HeadState {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        head_state(httparse::Response::new(&mut headers).parse(buffer))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /*
+     * An informational response such as `100 Continue`, followed by the
+     * real one. `101 Switching Protocols` is final.
+     */
+    pub fn is_interim(&self) -> bool {
+        (100..200).contains(&self.status) && self.status != 101
+    }
+
+    /*
+     * Whether the connection stops carrying HTTP after this response
+     */
+    pub fn switches_protocols(&self, request: &RequestHead) -> bool {
+        self.status == 101 || (request.method == "CONNECT" && (200..300).contains(&self.status))
+    }
+
+    /*
+     * Whether the backend leaves the connection open after this response
+     */
+    pub fn keep_alive(&self) -> bool {
+        keeps_alive(self.version, &self.headers)
+    }
+
+    /*
+     * How the body of this response to `request` is framed, or None when
+     * the framing headers are malformed
+     */
+    pub fn body_length(&self, request: &RequestHead) -> Option<BodyLength> {
+        if request.method == "HEAD" || matches!(self.status, 100..=199 | 204 | 304) {
+            return Some(BodyLength::Fixed(0));
+        }
+        body_framing(&self.headers)
+    }
+
+    /*
+     * Rewrites `head`, the raw bytes of this head, into the one sent to a
+     * client speaking HTTP/1.`client_version`. Hop-by-hop headers are
+     * replaced by the balancer's own decision to keep the client
+     * connection open or not. Responses that switch protocols are passed
+     * through as they are.
+     */
+    pub fn for_client(&self, head: &[u8], keep_alive: bool, client_version: u8) -> Vec<u8> {
+        if self.status == 101 {
+            return head.to_vec();
+        }
+
+        let listed = connection_tokens(&self.headers);
+        let dropped = |name: &str| is_hop_by_hop(name, &listed);
+        // Answer as HTTP/1.1 whatever the backend spoke, keeping the status
+        // and reason phrase
+        let status = first_line(head)
+            .splitn(2, |&byte| byte == b' ')
+            .nth(1)
+            .unwrap_or_default();
+        let status_line = [b"HTTP/1.1 ".as_slice(), status].concat();
+
+        let connection = match (keep_alive, client_version) {
+            (false, _) => Some("close"),
+            (true, 0) => Some("keep-alive"),
+            (true, _) => None,
+        };
+        let added: Vec<_> = connection
+            .map(|value| ("Connection", value.to_string()))
+            .into_iter()
+            .collect();
+
+        rewrite(head, Some(&status_line), dropped, &added)
+    }
+}
+
+fn owned_headers(headers: &[httparse::Header]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|header| {
+            (
+                header.name.to_string(),
+                String::from_utf8_lossy(header.value).into_owned(),
+            )
+        })
+        .collect()
+}
+
+fn head_state(result: httparse::Result<usize>) -> HeadState {
+    match result {
+        Ok(httparse::Status::Complete(_)) => HeadState::Complete,
+        Ok(httparse::Status::Partial) => HeadState::Partial,
+        Err(_) => HeadState::Invalid,
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/*
+ * Lower-cased options of every Connection header
+ */
+fn connection_tokens(headers: &[(String, String)]) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|(header, _)| header.eq_ignore_ascii_case("Connection"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+/*
+ * HTTP/1.1 connections persist unless closed explicitly, HTTP/1.0 ones only
+ * when asked to
+ */
+fn keeps_alive(version: u8, headers: &[(String, String)]) -> bool {
+    let tokens = connection_tokens(headers);
+    if version == 0 {
+        tokens.iter().any(|token| token == "keep-alive")
+    } else {
+        !tokens.iter().any(|token| token == "close")
+    }
+}
+
+fn is_hop_by_hop(name: &str, listed: &[String]) -> bool {
+    HOP_BY_HOP
+        .iter()
+        .any(|header| header.eq_ignore_ascii_case(name))
+        || listed.iter().any(|token| token.eq_ignore_ascii_case(name))
+}
+
+/*
+ * Body framing as declared by Transfer-Encoding and Content-Length, where
+ * chunked has to be the final coding and differing lengths are an error
+ */
+fn body_framing(headers: &[(String, String)]) -> Option<BodyLength> {
+    let codings: Vec<String> = headers
+        .iter()
+        .filter(|(header, _)| header.eq_ignore_ascii_case("Transfer-Encoding"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty())
+        .collect();
+    if let Some(last) = codings.last() {
+        return Some(if last == "chunked" {
+            BodyLength::Chunked
+        } else {
+            BodyLength::UntilClose
+        });
+    }
+
+    let mut length = None;
+    for (_, value) in headers
+        .iter()
+        .filter(|(header, _)| header.eq_ignore_ascii_case("Content-Length"))
+    {
+        for value in value.split(',') {
+            let value = value.trim();
+            if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+                return None;
+            }
+            let value: u64 = value.parse().ok()?;
+            if length.is_some_and(|length| length != value) {
+                return None;
+            }
+            length = Some(value);
+        }
+    }
+    Some(length.map_or(BodyLength::UntilClose, BodyLength::Fixed))
+}
+
+fn first_line(head: &[u8]) -> &[u8] {
+    head.split_inclusive(|&byte| byte == b'\n')
+        .next()
+        .unwrap_or_default()
+}
+
+/*
+ * Copies a raw head line by line, optionally with a different first line,
+ * leaving out headers for which `dropped` is true and appending `added`
+ * before the blank line. Kept headers pass through byte for byte.
+ */
+fn rewrite(
+    head: &[u8],
+    first: Option<&[u8]>,
+    dropped: impl Fn(&str) -> bool,
+    added: &[(&str, String)],
+) -> Vec<u8> {
+    let mut rewritten = Vec::with_capacity(head.len() + 256);
+    let mut lines = head.split_inclusive(|&byte| byte == b'\n');
+    let original_first = lines.next().unwrap_or_default();
+    rewritten.extend_from_slice(first.unwrap_or(original_first));
+
+    for line in lines {
+        if line.trim_ascii().is_empty() {
+            break;
+        }
+        let name = line.split(|&byte| byte == b':').next().unwrap_or_default();
+        if !dropped(&String::from_utf8_lossy(name.trim_ascii())) {
+            rewritten.extend_from_slice(line);
+        }
+    }
+    for (name, value) in added {
+        rewritten.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+    }
+    rewritten.extend_from_slice(b"\r\n");
+    rewritten
+}
+
+/*
+ * Writes a Forwarded parameter value as a token when it is one, otherwise as
+ * a quoted string
+ */
+fn quote(value: &str) -> String {
+    let is_tchar = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if !value.is_empty() && value.chars().all(is_tchar) {
+        return value.to_string();
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(head: &str) -> RequestHead {
+        RequestHead::parse(head.as_bytes()).unwrap()
+    }
+
+    fn response(head: &str) -> ResponseHead {
+        ResponseHead::parse(head.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn request_without_framing_has_no_body() {
+        let head = request("GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert_eq!(head.body_length(), Some(BodyLength::Fixed(0)));
+    }
+
+    #[test]
+    fn request_content_length() {
+        let head = request("POST / HTTP/1.1\r\nContent-Length: 42\r\n\r\n");
+        assert_eq!(head.body_length(), Some(BodyLength::Fixed(42)));
+        let head = request("POST / HTTP/1.1\r\nContent-Length: 7, 7\r\nContent-Length: 7\r\n\r\n");
+        assert_eq!(head.body_length(), Some(BodyLength::Fixed(7)));
+    }
+
+    #[test]
+    fn request_content_length_must_be_digits_and_agree() {
+        for value in ["+5", "-1", "0x10", "5 5", "", "5, 6"] {
+            let head = request(&format!(
+                "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+                value
+            ));
+            assert_eq!(head.body_length(), None, "{:?}", value);
+        }
+        let head = request("POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n");
+        assert_eq!(head.body_length(), None);
+    }
+
+    #[test]
+    fn request_chunked() {
+        let head = request("POST / HTTP/1.1\r\nTransfer-Encoding: gzip, Chunked\r\n\r\n");
+        assert_eq!(head.body_length(), Some(BodyLength::Chunked));
+    }
+
+    #[test]
+    fn request_not_ending_in_chunked_is_refused() {
+        let head = request("POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n");
+        assert_eq!(head.body_length(), None);
+    }
+
+    #[test]
+    fn chunked_wins_over_content_length_and_drops_it() {
+        let raw =
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let head = request(raw);
+        assert_eq!(head.body_length(), Some(BodyLength::Chunked));
+
+        let forwarding = Forwarding {
+            client: "192.0.2.1".parse().unwrap(),
+            proto: "http",
+            trusted: false,
+        };
+        let rewritten = head.for_backend(raw.as_bytes(), &forwarding, true);
+        let rewritten = String::from_utf8(rewritten).unwrap();
+        assert!(!rewritten.to_ascii_lowercase().contains("content-length"));
+        assert!(rewritten.contains("Transfer-Encoding: chunked\r\n"));
+    }
+
+    #[test]
+    fn response_framing() {
+        let get = request("GET / HTTP/1.1\r\n\r\n");
+        let head = request("HEAD / HTTP/1.1\r\n\r\n");
+
+        let chunked = response("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n");
+        assert_eq!(chunked.body_length(&get), Some(BodyLength::Chunked));
+        assert_eq!(chunked.body_length(&head), Some(BodyLength::Fixed(0)));
+
+        let fixed = response("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n");
+        assert_eq!(fixed.body_length(&get), Some(BodyLength::Fixed(3)));
+
+        let unframed = response("HTTP/1.1 200 OK\r\n\r\n");
+        assert_eq!(unframed.body_length(&get), Some(BodyLength::UntilClose));
+
+        let no_content = response("HTTP/1.1 204 No Content\r\nContent-Length: 3\r\n\r\n");
+        assert_eq!(no_content.body_length(&get), Some(BodyLength::Fixed(0)));
+
+        let conflicting =
+            response("HTTP/1.1 200 OK\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\n");
+        assert_eq!(conflicting.body_length(&get), None);
+    }
+
+    #[test]
+    fn status_line() {
+        assert_eq!(
+            parse_status(b"HTTP/1.1 503 Service Unavailable\r\n"),
+            Some(503)
+        );
+        assert_eq!(parse_status(b"HTTP/1.0 200"), Some(200));
+        assert_eq!(parse_status(b"garbage"), None);
+    }
+
+    /*
+     * The headers `raw` is sent to the backend with, for a client at `client`
+     */
+    fn forwarded(raw: &str, client: &str, trusted: bool) -> RequestHead {
+        let forwarding = Forwarding {
+            client: client.parse().unwrap(),
+            proto: "http",
+            trusted,
+        };
+        let rewritten = request(raw).for_backend(raw.as_bytes(), &forwarding, true);
+        RequestHead::parse(&rewritten).unwrap()
+    }
+
+    fn values<'a>(head: &'a RequestHead, name: &str) -> Vec<&'a str> {
+        head.headers
+            .iter()
+            .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    const SPOOFED: &str = "GET / HTTP/1.1\r\nHost: example.com\r\n\
+        X-Forwarded-For: 203.0.113.66\r\nX-Forwarded-Proto: https\r\n\
+        X-Forwarded-Host: evil.example\r\nForwarded: for=203.0.113.66\r\n\r\n";
+
+    #[test]
+    fn replaces_an_untrusted_clients_forwarding_headers() {
+        let head = forwarded(SPOOFED, "192.0.2.1", false);
+        assert_eq!(values(&head, "X-Forwarded-For"), ["192.0.2.1"]);
+        assert_eq!(values(&head, "X-Forwarded-Proto"), ["http"]);
+        assert_eq!(values(&head, "X-Forwarded-Host"), ["example.com"]);
+        assert_eq!(
+            values(&head, "Forwarded"),
+            ["for=192.0.2.1;host=example.com;proto=http"]
+        );
+    }
+
+    #[test]
+    fn appends_to_a_trusted_proxys_forwarding_headers() {
+        let head = forwarded(SPOOFED, "192.0.2.1", true);
+        assert_eq!(
+            values(&head, "X-Forwarded-For"),
+            ["203.0.113.66, 192.0.2.1"]
+        );
+        assert_eq!(values(&head, "X-Forwarded-Proto"), ["https"]);
+        assert_eq!(values(&head, "X-Forwarded-Host"), ["evil.example"]);
+        assert_eq!(
+            values(&head, "Forwarded"),
+            ["for=203.0.113.66, for=192.0.2.1;host=example.com;proto=http"]
+        );
+    }
+
+    #[test]
+    fn joins_repeated_forwarding_headers_of_a_trusted_proxy() {
+        let raw = "GET / HTTP/1.1\r\nX-Forwarded-For: 203.0.113.1\r\n\
+            X-Forwarded-For: 203.0.113.2, 203.0.113.3\r\n\r\n";
+        let head = forwarded(raw, "192.0.2.1", true);
+        assert_eq!(
+            values(&head, "X-Forwarded-For"),
+            ["203.0.113.1, 203.0.113.2, 203.0.113.3, 192.0.2.1"]
+        );
+        assert!(values(&head, "X-Forwarded-Host").is_empty());
+        assert_eq!(values(&head, "Forwarded"), ["for=192.0.2.1;proto=http"]);
+    }
+
+    #[test]
+    fn quotes_ipv6_clients_and_hosts_with_ports() {
+        let raw = "GET / HTTP/1.1\r\nHost: example.com:8080\r\n\r\n";
+        let head = forwarded(raw, "2001:db8::1", false);
+        assert_eq!(values(&head, "X-Forwarded-For"), ["2001:db8::1"]);
+        assert_eq!(
+            values(&head, "Forwarded"),
+            ["for=\"[2001:db8::1]\";host=\"example.com:8080\";proto=http"]
+        );
+
+        // A v4 client accepted on a dual-stack socket is named as v4
+        let head = forwarded(raw, "::ffff:192.0.2.1", false);
+        assert_eq!(values(&head, "X-Forwarded-For"), ["192.0.2.1"]);
+    }
+
+    #[test]
+    fn replaces_hop_by_hop_headers() {
+        let raw = "GET / HTTP/1.1\r\nConnection: close, X-Secret\r\nKeep-Alive: timeout=5\r\n\
+            X-Secret: 1\r\nX-Kept: 2\r\n\r\n";
+        let head = forwarded(raw, "192.0.2.1", false);
+        assert_eq!(values(&head, "Connection"), ["keep-alive"]);
+        assert!(values(&head, "Keep-Alive").is_empty());
+        assert!(values(&head, "X-Secret").is_empty());
+        assert_eq!(values(&head, "X-Kept"), ["2"]);
+    }
+}
@@ -0,0 +1,20 @@
+pub mod access;
+pub mod admin;
+pub mod backend;
+pub mod balancer;
+pub mod config;
+pub mod health;
+pub mod http;
+pub mod metrics;
+pub mod outlier;
+pub mod proxy;
+pub mod relay;
+pub mod reload;
+pub mod retry;
+pub mod router;
+pub mod shutdown;
+pub mod sni;
+pub mod strategy;
+pub mod stream;
+pub mod tls;
+pub mod upstream;
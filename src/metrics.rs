@@ -0,0 +1,379 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{backend::Pool, strategy::Outcome};
+
+/*
+ * Upper bounds of the latency histogram buckets, in seconds
+ */
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/*
+ * The balancer's own error responses, counted by status
+ */
+const STATUSES: [u16; 7] = [400, 404, 408, 431, 502, 503, 504];
+
+/*
+ * Counters that belong to no pool
+ */
+pub static TOTALS: Totals = Totals::new();
+
+pub struct Totals {
+    connections_accepted: AtomicU64,
+    connections_closed: AtomicU64,
+    requests: AtomicU64,
+    responses: [AtomicU64; STATUSES.len()],
+}
+
+impl Totals {
+    const fn new() -> Self {
+        Totals {
+            connections_accepted: AtomicU64::new(0),
+            connections_closed: AtomicU64::new(0),
+            requests: AtomicU64::new(0),
+            responses: [const { AtomicU64::new(0) }; STATUSES.len()],
+        }
+    }
+
+    pub fn connection_accepted(&self) {
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections_closed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /*
+     * A request head read from a client, whether or not it got anywhere
+     */
+    pub fn request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /*
+     * One of the balancer's own responses, given as sent
+     */
+    pub fn responded(&self, response: &str) {
+        let status = response.get(9..12).and_then(|status| status.parse().ok());
+        if let Some(index) = STATUSES.iter().position(|known| Some(*known) == status) {
+            self.responses[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/*
+ * Cumulative counts of observations at or below each bucket bound, as
+ * Prometheus expects them
+ */
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name,
+                labels,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+/*
+ * Traffic and health check counts of one backend. They live as long as the
+ * backend stays in its pool, reloads included.
+ */
+#[derive(Debug, Default)]
+pub struct BackendStats {
+    /*
+     * Requests or passthrough connections, each attempt counted once
+     */
+    requests: AtomicU64,
+    /*
+     * Attempts that failed or got a 5xx
+     */
+    errors: AtomicU64,
+    /*
+     * Responses by status class, 1xx to 5xx
+     */
+    responses: [AtomicU64; 5],
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    checks_passed: AtomicU64,
+    checks_failed: AtomicU64,
+    duration: Histogram,
+}
+
+impl BackendStats {
+    pub fn completed(&self, outcome: &Outcome) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if !outcome.success {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(status) = outcome.status {
+            let class = usize::from(status / 100).clamp(1, 5);
+            self.responses[class - 1].fetch_add(1, Ordering::Relaxed);
+        }
+        self.duration.observe(outcome.duration);
+    }
+
+    pub fn transferred(&self, sent: u64, received: u64) {
+        self.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+        self.bytes_received.fetch_add(received, Ordering::Relaxed);
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    pub fn checked(&self, passed: bool) {
+        let counter = if passed {
+            &self.checks_passed
+        } else {
+            &self.checks_failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/*
+ * Everything in the Prometheus text exposition format
+ */
+pub fn render(pools: &HashMap<String, Arc<Pool>>) -> String {
+    let mut out = String::new();
+    let accepted = TOTALS.connections_accepted.load(Ordering::Relaxed);
+    let closed = TOTALS.connections_closed.load(Ordering::Relaxed);
+    let totals = [
+        (
+            "baalancer_connections_accepted_total",
+            "counter",
+            "Client connections accepted.",
+            accepted,
+        ),
+        (
+            "baalancer_connections_open",
+            "gauge",
+            "Client connections currently open.",
+            accepted.saturating_sub(closed),
+        ),
+        (
+            "baalancer_requests_total",
+            "counter",
+            "Request heads read from clients.",
+            TOTALS.requests.load(Ordering::Relaxed),
+        ),
+    ];
+    for (name, kind, help, value) in totals {
+        describe(&mut out, name, kind, help);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+    describe(
+        &mut out,
+        "baalancer_responses_total",
+        "counter",
+        "Error responses the balancer sent itself, by status.",
+    );
+    for (status, count) in STATUSES.iter().zip(&TOTALS.responses) {
+        let _ = writeln!(
+            out,
+            "baalancer_responses_total{{status=\"{}\"}} {}",
+            status,
+            count.load(Ordering::Relaxed)
+        );
+    }
+
+    let mut pools: Vec<_> = pools.values().collect();
+    pools.sort_by(|a, b| a.name().cmp(b.name()));
+    let backends: Vec<_> = pools
+        .iter()
+        .flat_map(|pool| {
+            pool.backends().into_iter().map(|backend| {
+                let labels = format!(
+                    "pool=\"{}\",backend=\"{}\"",
+                    escape(pool.name()),
+                    escape(backend.address())
+                );
+                (labels, backend)
+            })
+        })
+        .collect();
+
+    type Counter = fn(&BackendStats) -> &AtomicU64;
+    let counters: [(&str, &str, Counter); 4] = [
+        (
+            "baalancer_backend_requests_total",
+            "Requests and passthrough connections sent to the backend, retries included.",
+            |stats| &stats.requests,
+        ),
+        (
+            "baalancer_backend_errors_total",
+            "Attempts that failed or got a 5xx response.",
+            |stats| &stats.errors,
+        ),
+        (
+            "baalancer_backend_sent_bytes_total",
+            "Bytes sent to the backend.",
+            |stats| &stats.bytes_sent,
+        ),
+        (
+            "baalancer_backend_received_bytes_total",
+            "Bytes received from the backend.",
+            |stats| &stats.bytes_received,
+        ),
+    ];
+    for (name, help, counter) in counters {
+        describe(&mut out, name, "counter", help);
+        for (labels, backend) in &backends {
+            let value = counter(backend.stats()).load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+
+    describe(
+        &mut out,
+        "baalancer_backend_responses_total",
+        "counter",
+        "Responses from the backend by status class.",
+    );
+    for (labels, backend) in &backends {
+        for (class, count) in backend.stats().responses.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "baalancer_backend_responses_total{{{},code=\"{}xx\"}} {}",
+                labels,
+                class + 1,
+                count.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    describe(
+        &mut out,
+        "baalancer_health_checks_total",
+        "counter",
+        "Active health checks of the backend by result.",
+    );
+    for (labels, backend) in &backends {
+        let stats = backend.stats();
+        for (result, count) in [
+            ("pass", &stats.checks_passed),
+            ("fail", &stats.checks_failed),
+        ] {
+            let _ = writeln!(
+                out,
+                "baalancer_health_checks_total{{{},result=\"{}\"}} {}",
+                labels,
+                result,
+                count.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    describe(
+        &mut out,
+        "baalancer_backend_up",
+        "gauge",
+        "Whether the backend is healthy and not ejected.",
+    );
+    for (labels, backend) in &backends {
+        let up = backend.is_healthy() && !backend.is_ejected();
+        let _ = writeln!(out, "baalancer_backend_up{{{}}} {}", labels, u8::from(up));
+    }
+
+    describe(
+        &mut out,
+        "baalancer_backend_active_connections",
+        "gauge",
+        "Connections currently served by the backend.",
+    );
+    for (labels, backend) in &backends {
+        let _ = writeln!(
+            out,
+            "baalancer_backend_active_connections{{{}}} {}",
+            labels,
+            backend.active_connections()
+        );
+    }
+
+    describe(
+        &mut out,
+        "baalancer_backend_request_duration_seconds",
+        "histogram",
+        "Time from picking the backend to the end of the response or connection.",
+    );
+    for (labels, backend) in &backends {
+        backend.stats().duration.render(
+            &mut out,
+            "baalancer_backend_request_duration_seconds",
+            labels,
+        );
+    }
+
+    describe(
+        &mut out,
+        "baalancer_retries_total",
+        "counter",
+        "Retries on another backend the retry budget allowed.",
+    );
+    for pool in &pools {
+        let _ = writeln!(
+            out,
+            "baalancer_retries_total{{pool=\"{}\"}} {}",
+            escape(pool.name()),
+            pool.retry_budget().retried()
+        );
+    }
+    out
+}
+
+fn describe(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+/*
+ * Label values are quoted, so backslashes, quotes and newlines need escaping
+ */
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,214 @@
+use std::time::{Duration, Instant};
+
+use crate::config::OutlierDetectionConfig;
+
+/*
+ * Something live traffic revealed about a backend
+ */
+#[derive(Clone, Copy, Debug)]
+pub enum Observation {
+    ConnectFailure,
+    Connected,
+    /*
+     * The backend accepted the request but closed or reset before answering;
+     * counted like a 5xx
+     */
+    NoResponse,
+    Response(u16),
+}
+
+/*
+ * Per-backend bookkeeping for passive health checking
+ */
+#[derive(Debug, Default)]
+pub struct OutlierState {
+    consecutive_5xx: u32,
+    consecutive_connect_failures: u32,
+    /*
+     * Times ejected since the backend last stayed clean for `max_ejection`,
+     * drives the exponential back-off
+     */
+    ejections: u32,
+    ejected_until: Option<Instant>,
+}
+
+impl OutlierState {
+    /*
+     * Counts one observation. Returns the reason when it pushed the backend
+     * over one of the thresholds.
+     */
+    pub fn observe(
+        &mut self,
+        observation: Observation,
+        config: &OutlierDetectionConfig,
+        now: Instant,
+    ) -> Option<String> {
+        match observation {
+            Observation::ConnectFailure => {
+                self.consecutive_connect_failures += 1;
+                (self.consecutive_connect_failures >= config.consecutive_connect_failures).then(
+                    || {
+                        format!(
+                            "{} consecutive connect failures",
+                            self.consecutive_connect_failures
+                        )
+                    },
+                )
+            }
+            Observation::Connected => {
+                self.consecutive_connect_failures = 0;
+                None
+            }
+            Observation::NoResponse => {
+                self.consecutive_5xx += 1;
+                (self.consecutive_5xx >= config.consecutive_5xx).then(|| {
+                    format!(
+                        "{} consecutive 5xx responses or resets",
+                        self.consecutive_5xx
+                    )
+                })
+            }
+            Observation::Response(status) if status >= 500 => {
+                self.consecutive_5xx += 1;
+                (self.consecutive_5xx >= config.consecutive_5xx)
+                    .then(|| format!("{} consecutive 5xx responses", self.consecutive_5xx))
+            }
+            Observation::Response(_) => {
+                self.consecutive_5xx = 0;
+                if self
+                    .ejected_until
+                    .is_some_and(|until| now.saturating_duration_since(until) > config.max_ejection)
+                {
+                    self.ejections = 0;
+                    self.ejected_until = None;
+                }
+                None
+            }
+        }
+    }
+
+    /*
+     * Takes the backend out for `base_ejection` doubled for every earlier
+     * ejection, capped at `max_ejection`
+     */
+    pub fn eject(&mut self, config: &OutlierDetectionConfig, now: Instant) -> Duration {
+        let factor = 1u32 << self.ejections.min(16);
+        let duration = config
+            .base_ejection
+            .saturating_mul(factor)
+            .min(config.max_ejection);
+
+        self.ejections += 1;
+        self.consecutive_5xx = 0;
+        self.consecutive_connect_failures = 0;
+        self.ejected_until = Some(now + duration);
+        duration
+    }
+
+    /*
+     * Forgets a tripped threshold that could not be acted on, so the next
+     * failure does not immediately trip it again
+     */
+    pub fn reset_counters(&mut self) {
+        self.consecutive_5xx = 0;
+        self.consecutive_connect_failures = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> OutlierDetectionConfig {
+        toml::from_str(
+            "consecutive_5xx = 3\nconsecutive_connect_failures = 2\nbase_ejection_ms = 1000\nmax_ejection_ms = 5000",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn trips_after_consecutive_failures_only() {
+        let config = config();
+        let now = Instant::now();
+        let mut state = OutlierState::default();
+
+        assert!(state
+            .observe(Observation::Response(500), &config, now)
+            .is_none());
+        assert!(state
+            .observe(Observation::NoResponse, &config, now)
+            .is_none());
+        assert!(state
+            .observe(Observation::Response(200), &config, now)
+            .is_none());
+        assert!(state
+            .observe(Observation::Response(502), &config, now)
+            .is_none());
+        assert!(state
+            .observe(Observation::Response(503), &config, now)
+            .is_none());
+        assert!(state
+            .observe(Observation::Response(504), &config, now)
+            .is_some());
+
+        assert!(state
+            .observe(Observation::ConnectFailure, &config, now)
+            .is_none());
+        assert!(state
+            .observe(Observation::Connected, &config, now)
+            .is_none());
+        assert!(state
+            .observe(Observation::ConnectFailure, &config, now)
+            .is_none());
+        assert!(state
+            .observe(Observation::ConnectFailure, &config, now)
+            .is_some());
+    }
+
+    #[test]
+    fn ejection_doubles_up_to_the_maximum() {
+        let config = config();
+        let now = Instant::now();
+        let mut state = OutlierState::default();
+        let durations: Vec<u64> = (0..5)
+            .map(|_| state.eject(&config, now).as_millis() as u64)
+            .collect();
+        assert_eq!(durations, [1000, 2000, 4000, 5000, 5000]);
+    }
+
+    #[test]
+    fn ejecting_clears_the_counters() {
+        let config = config();
+        let now = Instant::now();
+        let mut state = OutlierState::default();
+        for _ in 0..2 {
+            state.observe(Observation::Response(500), &config, now);
+        }
+        state.eject(&config, now);
+        for _ in 0..2 {
+            assert!(state
+                .observe(Observation::Response(500), &config, now)
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn backoff_resets_after_staying_clean_for_the_maximum() {
+        let config = config();
+        let now = Instant::now();
+        let mut state = OutlierState::default();
+        assert_eq!(state.eject(&config, now), Duration::from_secs(1));
+        assert_eq!(state.eject(&config, now), Duration::from_secs(2));
+
+        // Shortly after the ejection ended the backoff still stands
+        let later = now + Duration::from_secs(4);
+        state.observe(Observation::Response(200), &config, later);
+        assert_eq!(state.eject(&config, later), Duration::from_secs(4));
+
+        // Clean for longer than max_ejection past its end starts over
+        let much_later =
+            later + Duration::from_secs(4) + config.max_ejection + Duration::from_secs(1);
+        state.observe(Observation::Response(200), &config, much_later);
+        assert_eq!(state.eject(&config, much_later), Duration::from_secs(1));
+    }
+}
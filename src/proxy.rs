@@ -0,0 +1,362 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::{io::AsyncReadExt, net::TcpStream};
+
+use crate::config::ProxyVersion;
+
+/*
+ * Opens every v2 header; no v1 header or HTTP request can start like this
+ */
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/*
+ * Longest v1 header the specification allows, CRLF included
+ */
+const MAX_V1: usize = 107;
+
+const V2_LOCAL: u8 = 0x20;
+const V2_PROXY: u8 = 0x21;
+const TCP4: u8 = 0x11;
+const TCP6: u8 = 0x21;
+
+/*
+ * Both ends of a client connection as seen by whoever accepted it first
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Addresses {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+/*
+ * Reads a v1 or v2 header off the start of `stream`, consuming nothing
+ * beyond it, so TLS or HTTP can take over the socket as it is. Returns None
+ * for headers that carry no addresses, such as the health checks of the
+ * proxy in front.
+ */
+pub async fn read_header(stream: &mut TcpStream) -> io::Result<Option<Addresses>> {
+    // Even the shortest v1 header, `PROXY UNKNOWN\r\n`, is longer
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+    if &start == SIGNATURE {
+        let mut fixed = [0; 4];
+        stream.read_exact(&mut fixed).await?;
+        let [command, family, high, low] = fixed;
+        let mut payload = vec![0; usize::from(u16::from_be_bytes([high, low]))];
+        stream.read_exact(&mut payload).await?;
+        return parse_v2(command, family, &payload).ok_or_else(|| invalid("malformed v2 header"));
+    }
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid("no PROXY protocol header"));
+    }
+
+    // The v1 line has no length up front: peek at what has arrived and take
+    // it up to the line feed, leaving whatever follows
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\n") {
+        let mut buffer = [0; MAX_V1];
+        let room = MAX_V1 - line.len();
+        if room == 0 {
+            return Err(invalid("v1 header too long"));
+        }
+        let peeked = match stream.peek(&mut buffer[..room]).await? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => &mut buffer[..n],
+        };
+        let take = peeked
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .map_or(peeked.len(), |at| at + 1);
+        stream.read_exact(&mut peeked[..take]).await?;
+        line.extend_from_slice(&peeked[..take]);
+    }
+    if !line.ends_with(b"\r\n") {
+        return Err(invalid("malformed v1 header"));
+    }
+    let line = std::str::from_utf8(&line).map_err(|_| invalid("malformed v1 header"))?;
+    parse_v1(line).ok_or_else(|| invalid("malformed v1 header"))
+}
+
+fn parse_v1(line: &str) -> Option<Option<Addresses>> {
+    let fields: Vec<&str> = line.trim_end_matches("\r\n").split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Some(None),
+        ["PROXY", family, source, destination, source_port, destination_port] => {
+            let ip = |address: &str| -> Option<IpAddr> {
+                match *family {
+                    "TCP4" => address.parse::<Ipv4Addr>().ok().map(IpAddr::V4),
+                    "TCP6" => address.parse::<Ipv6Addr>().ok().map(IpAddr::V6),
+                    _ => None,
+                }
+            };
+            Some(Some(Addresses {
+                source: SocketAddr::new(ip(source)?, source_port.parse().ok()?),
+                destination: SocketAddr::new(ip(destination)?, destination_port.parse().ok()?),
+            }))
+        }
+        _ => None,
+    }
+}
+
+fn parse_v2(command: u8, family: u8, payload: &[u8]) -> Option<Option<Addresses>> {
+    match command {
+        V2_LOCAL => return Some(None),
+        V2_PROXY => {}
+        _ => return None,
+    }
+    let port = |at: usize| u16::from_be_bytes([payload[at], payload[at + 1]]);
+    let addresses = match family {
+        TCP4 if payload.len() >= 12 => {
+            let ip = |at: usize| Ipv4Addr::from(<[u8; 4]>::try_from(&payload[at..at + 4]).unwrap());
+            Addresses {
+                source: SocketAddr::new(ip(0).into(), port(8)),
+                destination: SocketAddr::new(ip(4).into(), port(10)),
+            }
+        }
+        TCP6 if payload.len() >= 36 => {
+            let ip =
+                |at: usize| Ipv6Addr::from(<[u8; 16]>::try_from(&payload[at..at + 16]).unwrap());
+            Addresses {
+                source: SocketAddr::new(ip(0).into(), port(32)),
+                destination: SocketAddr::new(ip(16).into(), port(34)),
+            }
+        }
+        TCP4 | TCP6 => return None,
+        // UDP and Unix sockets tell nothing about a TCP client
+        _ => return Some(None),
+    };
+    Some(Some(addresses))
+}
+
+/*
+ * The header that introduces a client connection to a backend. Addresses
+ * of different families are both given as IPv6.
+ */
+pub fn header(version: ProxyVersion, addresses: &Addresses) -> Vec<u8> {
+    let (source, destination) = match (addresses.source.ip(), addresses.destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => (source.into(), destination.into()),
+        (source, destination) => (
+            IpAddr::V6(to_ipv6(source)),
+            IpAddr::V6(to_ipv6(destination)),
+        ),
+    };
+    let (source_port, destination_port) = (addresses.source.port(), addresses.destination.port());
+
+    match version {
+        ProxyVersion::V1 => {
+            let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                family, source, destination, source_port, destination_port
+            )
+            .into_bytes()
+        }
+        ProxyVersion::V2 => {
+            let mut header = SIGNATURE.to_vec();
+            let (family, payload) = match (source, destination) {
+                (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                    (TCP4, [source.octets(), destination.octets()].concat())
+                }
+                (source, destination) => (
+                    TCP6,
+                    [to_ipv6(source).octets(), to_ipv6(destination).octets()].concat(),
+                ),
+            };
+            header.extend_from_slice(&[V2_PROXY, family]);
+            header.extend_from_slice(&(payload.len() as u16 + 4).to_be_bytes());
+            header.extend_from_slice(&payload);
+            header.extend_from_slice(&source_port.to_be_bytes());
+            header.extend_from_slice(&destination_port.to_be_bytes());
+            header
+        }
+    }
+}
+
+/*
+ * The header for connections the balancer makes on its own account, such
+ * as health checks, which have no client to name
+ */
+pub fn local_header(version: ProxyVersion) -> Vec<u8> {
+    match version {
+        ProxyVersion::V1 => b"PROXY UNKNOWN\r\n".to_vec(),
+        ProxyVersion::V2 => [SIGNATURE.as_slice(), &[V2_LOCAL, 0, 0, 0]].concat(),
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    /*
+     * Reads a header off a socket the pieces are sent to one after another,
+     * then whatever the client sent after it
+     */
+    async fn read_pieces(pieces: &[&[u8]]) -> (io::Result<Option<Addresses>>, Vec<u8>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let pieces: Vec<Vec<u8>> = pieces.iter().map(|piece| piece.to_vec()).collect();
+        let sender = tokio::spawn(async move {
+            for piece in pieces {
+                client.write_all(&piece).await.unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            client.shutdown().await.unwrap();
+        });
+
+        let result = read_header(&mut stream).await;
+        sender.await.unwrap();
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        (result, rest)
+    }
+
+    async fn read(input: &[u8]) -> (io::Result<Option<Addresses>>, Vec<u8>) {
+        read_pieces(&[input]).await
+    }
+
+    fn addresses(source: &str, destination: &str) -> Addresses {
+        Addresses {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_v1_and_leaves_the_request() {
+        let (result, rest) =
+            read(b"PROXY TCP4 192.0.2.1 198.51.100.2 51234 443\r\nGET / HTTP/1.1\r\n").await;
+        assert_eq!(
+            result.unwrap(),
+            Some(addresses("192.0.2.1:51234", "198.51.100.2:443"))
+        );
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        let (result, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 1 2\r\n").await;
+        assert_eq!(
+            result.unwrap(),
+            Some(addresses("[2001:db8::1]:1", "[2001:db8::2]:2"))
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_v1_sent_in_pieces() {
+        let (result, rest) = read_pieces(&[
+            b"PROXY TCP4 192.0.2.1",
+            b" 198.51.100.2 51234 443\r",
+            b"\nGET / HTTP/1.1\r\n",
+        ])
+        .await;
+        assert_eq!(
+            result.unwrap(),
+            Some(addresses("192.0.2.1:51234", "198.51.100.2:443"))
+        );
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn v1_unknown_carries_no_addresses() {
+        let (result, rest) = read(b"PROXY UNKNOWN\r\nrest").await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"rest");
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_v1_headers() {
+        for input in [
+            &b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 51234\r\n",
+            b"PROXY TCP4 2001:db8::1 198.51.100.2 1 2\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 1 65536\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.2 1 2\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 1 2",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 1 2\nrest",
+        ] {
+            let (result, _) = read(input).await;
+            assert!(result.is_err(), "{:?}", String::from_utf8_lossy(input));
+        }
+
+        let mut long = b"PROXY TCP4 ".to_vec();
+        long.resize(200, b'1');
+        let (result, _) = read(&long).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn reads_v2() {
+        for (source, destination) in [
+            ("192.0.2.1:51234", "198.51.100.2:443"),
+            ("[2001:db8::1]:1", "[2001:db8::2]:2"),
+        ] {
+            let expected = addresses(source, destination);
+            let mut input = header(ProxyVersion::V2, &expected);
+            input.extend_from_slice(b"\x16\x03\x01");
+            let (result, rest) = read(&input).await;
+            assert_eq!(result.unwrap(), Some(expected));
+            assert_eq!(rest, b"\x16\x03\x01");
+        }
+    }
+
+    #[tokio::test]
+    async fn v2_local_and_unix_carry_no_addresses() {
+        let (result, rest) = read(&local_header(ProxyVersion::V2)).await;
+        assert_eq!(result.unwrap(), None);
+        assert!(rest.is_empty());
+
+        let mut unix = SIGNATURE.to_vec();
+        unix.extend_from_slice(&[V2_PROXY, 0x31, 0, 2, b'/', 0]);
+        let (result, _) = read(&unix).await;
+        assert_eq!(result.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_v2_headers() {
+        let mut short = SIGNATURE.to_vec();
+        short.extend_from_slice(&[V2_PROXY, TCP4, 0, 4, 1, 2, 3, 4]);
+        let (result, _) = read(&short).await;
+        assert!(result.is_err());
+
+        let mut command = SIGNATURE.to_vec();
+        command.extend_from_slice(&[0x22, TCP4, 0, 0]);
+        let (result, _) = read(&command).await;
+        assert!(result.is_err());
+
+        let mut truncated = header(
+            ProxyVersion::V2,
+            &addresses("192.0.2.1:1", "198.51.100.2:2"),
+        );
+        truncated.pop();
+        let (result, _) = read(&truncated).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn writes_mixed_families_as_ipv6() {
+        let header = header(
+            ProxyVersion::V1,
+            &addresses("192.0.2.1:1", "[2001:db8::2]:2"),
+        );
+        assert_eq!(
+            header,
+            b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 1 2\r\n".to_vec()
+        );
+    }
+}
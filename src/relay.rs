@@ -0,0 +1,806 @@
+use std::{
+    fmt,
+    future::Future,
+    io,
+    pin::pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time,
+};
+
+use crate::{
+    http::{self, BodyLength, HeadState},
+    stream::Stream,
+};
+
+/*
+ * Leading bytes of each direction kept for inspection, enough for a status
+ * line
+ */
+const HEAD_CAPTURE: usize = 64;
+
+/*
+ * Time limits applied while relaying
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /*
+     * Longest stretch without traffic in either direction
+     */
+    pub idle: Duration,
+    /*
+     * Longest the backend may stay silent after the client last sent
+     * something, until its response starts
+     */
+    pub first_byte: Duration,
+    pub deadline: Option<Instant>,
+}
+
+impl Limits {
+    /*
+     * Caps `timeout` by the time left until the deadline, returning the
+     * limit that will be the one to expire
+     */
+    pub fn bound(
+        &self,
+        timeout: Duration,
+        cause: TimeoutCause,
+    ) -> Result<(Duration, TimeoutCause), TimeoutCause> {
+        let Some(deadline) = self.deadline else {
+            return Ok((timeout, cause));
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            Err(TimeoutCause::Deadline)
+        } else if remaining < timeout {
+            Ok((remaining, TimeoutCause::Deadline))
+        } else {
+            Ok((timeout, cause))
+        }
+    }
+
+    /*
+     * Runs one I/O operation that may take up to `timeout`, or less if the
+     * deadline comes first
+     */
+    pub async fn within<T>(
+        &self,
+        timeout: Duration,
+        cause: TimeoutCause,
+        operation: impl Future<Output = io::Result<T>>,
+    ) -> Result<T, Stop> {
+        let (wait, cause) = self.bound(timeout, cause).map_err(Stop::Timeout)?;
+        match time::timeout(wait, operation).await {
+            Ok(result) => result.map_err(Stop::Io),
+            Err(_) => Err(Stop::Timeout(cause)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeoutCause {
+    Connect,
+    FirstByte,
+    Idle,
+    Deadline,
+}
+
+impl fmt::Display for TimeoutCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TimeoutCause::Connect => "connect timeout",
+            TimeoutCause::FirstByte => "no response within the first byte timeout",
+            TimeoutCause::Idle => "idle timeout",
+            TimeoutCause::Deadline => "request deadline exceeded",
+        })
+    }
+}
+
+/*
+ * What passed through a finished relay
+ */
+#[derive(Clone, Debug, Default)]
+pub struct RelaySummary {
+    pub bytes_to_backend: u64,
+    pub bytes_to_client: u64,
+    /*
+     * Status of the backend's response, when it answered with HTTP
+     */
+    pub response_status: Option<u16>,
+    /*
+     * Set when the relay was cut short by one of the `Limits`. The client
+     * connection is left open in that case so an error can still be sent.
+     */
+    pub timeout: Option<TimeoutCause>,
+}
+
+/*
+ * Traffic exchanged before the relay took over: the buffered request was
+ * already written to the backend, and `response` holds bytes already read
+ * from the backend that still have to reach the client
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Preamble {
+    pub request_bytes: u64,
+    pub response: Vec<u8>,
+}
+
+#[derive(Default)]
+struct HalfSummary {
+    bytes: u64,
+    head: Vec<u8>,
+}
+
+impl HalfSummary {
+    fn record(&mut self, data: &[u8]) {
+        if self.head.len() < HEAD_CAPTURE {
+            let take = data.len().min(HEAD_CAPTURE - self.head.len());
+            self.head.extend_from_slice(&data[..take]);
+        }
+        self.bytes += data.len() as u64;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Upstream,
+    Downstream,
+}
+
+/*
+ * State both directions consult to tell idleness apart from a one-sided
+ * transfer
+ */
+struct Activity {
+    started: Instant,
+    last_upstream: AtomicU64,
+    last_downstream: AtomicU64,
+    response_started: AtomicBool,
+}
+
+impl Activity {
+    fn new(response_started: bool) -> Self {
+        Activity {
+            started: Instant::now(),
+            last_upstream: AtomicU64::new(0),
+            last_downstream: AtomicU64::new(0),
+            response_started: AtomicBool::new(response_started),
+        }
+    }
+
+    fn touch(&self, direction: Direction) {
+        let now = self.started.elapsed().as_millis() as u64;
+        match direction {
+            Direction::Upstream => self.last_upstream.store(now, Ordering::Relaxed),
+            Direction::Downstream => {
+                self.last_downstream.store(now, Ordering::Relaxed);
+                self.response_started.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn since(&self, last: &AtomicU64) -> Duration {
+        self.started
+            .elapsed()
+            .saturating_sub(Duration::from_millis(last.load(Ordering::Relaxed)))
+    }
+
+    /*
+     * How long `direction` may wait in its next read, or the limit that has
+     * run out. Until the response starts the first byte timeout stands in
+     * for the idle timeout, so a backend that takes a while to think is not
+     * mistaken for an idle connection.
+     */
+    fn wait(&self, direction: Direction, limits: &Limits) -> Result<Duration, TimeoutCause> {
+        let (limit, elapsed, cause) = if self.response_started.load(Ordering::Relaxed) {
+            let since_upstream = self.since(&self.last_upstream);
+            let since_downstream = self.since(&self.last_downstream);
+            (
+                limits.idle,
+                since_upstream.min(since_downstream),
+                TimeoutCause::Idle,
+            )
+        } else {
+            (
+                limits.first_byte,
+                self.since(&self.last_upstream),
+                TimeoutCause::FirstByte,
+            )
+        };
+        let (mut remaining, mut cause) = (limit.saturating_sub(elapsed), cause);
+
+        // Only the backend's side reports a missing first byte; the client's
+        // side simply keeps waiting alongside it
+        if direction == Direction::Upstream && cause == TimeoutCause::FirstByte {
+            remaining = remaining.max(Duration::from_millis(100));
+        }
+
+        if let Some(deadline) = limits.deadline {
+            let until_deadline = deadline.saturating_duration_since(Instant::now());
+            if until_deadline <= remaining {
+                remaining = until_deadline;
+                cause = TimeoutCause::Deadline;
+            }
+        }
+
+        if remaining.is_zero() {
+            Err(cause)
+        } else {
+            Ok(remaining)
+        }
+    }
+}
+
+/*
+ * Why moving bytes stopped short
+ */
+#[derive(Debug)]
+pub enum Stop {
+    Timeout(TimeoutCause),
+    Io(io::Error),
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stop::Timeout(cause) => cause.fmt(f),
+            Stop::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+/*
+ * Which end of a body transfer failed
+ */
+#[derive(Debug)]
+pub enum BodyError {
+    Read(Stop),
+    Write(Stop),
+}
+
+/*
+ * Which side of a relay broke the connection
+ */
+#[derive(Debug)]
+pub enum RelayError {
+    Client(io::Error),
+    Backend(io::Error),
+}
+
+impl From<RelayError> for io::Error {
+    fn from(error: RelayError) -> Self {
+        match error {
+            RelayError::Client(e) | RelayError::Backend(e) => e,
+        }
+    }
+}
+
+/*
+ * Longest chunk size or trailer line accepted in a chunked body
+ */
+const MAX_LINE: u64 = 4096;
+
+/*
+ * Copies bytes client -> backend and backend -> client concurrently until
+ * both sides have half-closed. EOF on one side is propagated as a write
+ * shutdown to the other. An error or a limit running out in either
+ * direction abandons both and closes the backend; the client connection is
+ * handed back to the caller, which decides what it still gets to see. An
+ * error names the side whose socket failed.
+ */
+pub async fn relay(
+    client: &mut Stream,
+    backend: Stream,
+    preamble: Preamble,
+    limits: Limits,
+) -> Result<RelaySummary, RelayError> {
+    let activity = Activity::new(!preamble.response.is_empty());
+    let (mut client_reader, mut client_writer) = tokio::io::split(client);
+    let (mut backend_reader, mut backend_writer) = tokio::io::split(backend);
+
+    let mut sent = HalfSummary {
+        bytes: preamble.request_bytes,
+        head: Vec::new(),
+    };
+    let mut received = HalfSummary::default();
+
+    let outcome = {
+        let upstream = copy_half(
+            &mut client_reader,
+            &mut backend_writer,
+            &mut sent,
+            Direction::Upstream,
+            &activity,
+            &limits,
+        );
+        let downstream = async {
+            write_within(&mut client_writer, &preamble.response, limits.idle)
+                .await
+                .map_err(BodyError::Write)?;
+            received.record(&preamble.response);
+            copy_half(
+                &mut backend_reader,
+                &mut client_writer,
+                &mut received,
+                Direction::Downstream,
+                &activity,
+                &limits,
+            )
+            .await
+        };
+
+        let mut upstream = pin!(upstream);
+        let mut downstream = pin!(downstream);
+        let (mut upstream_done, mut downstream_done) = (false, false);
+        loop {
+            // Whichever direction stops early drops the other one with it
+            let (direction, result) = tokio::select! {
+                result = &mut upstream, if !upstream_done => {
+                    upstream_done = true;
+                    (Direction::Upstream, result)
+                }
+                result = &mut downstream, if !downstream_done => {
+                    downstream_done = true;
+                    (Direction::Downstream, result)
+                }
+            };
+            if result.is_err() || (upstream_done && downstream_done) {
+                break result.map_err(|error| (direction, error));
+            }
+        }
+    };
+
+    let timeout = match outcome {
+        Ok(()) => None,
+        Err((
+            _,
+            BodyError::Read(Stop::Timeout(cause)) | BodyError::Write(Stop::Timeout(cause)),
+        )) => Some(cause),
+        // Upstream reads from the client and writes to the backend
+        Err((Direction::Upstream, BodyError::Read(Stop::Io(e))))
+        | Err((Direction::Downstream, BodyError::Write(Stop::Io(e)))) => {
+            return Err(RelayError::Client(e))
+        }
+        Err((Direction::Upstream, BodyError::Write(Stop::Io(e))))
+        | Err((Direction::Downstream, BodyError::Read(Stop::Io(e)))) => {
+            return Err(RelayError::Backend(e))
+        }
+    };
+    Ok(RelaySummary {
+        bytes_to_backend: sent.bytes,
+        bytes_to_client: received.bytes,
+        response_status: http::parse_status(&received.head),
+        timeout,
+    })
+}
+
+/*
+ * Copies one direction until EOF, then half-closes the writer, remembering
+ * the first `HEAD_CAPTURE` bytes and enforcing the relay's time limits
+ */
+async fn copy_half<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    summary: &mut HalfSummary,
+    direction: Direction,
+    activity: &Activity,
+    limits: &Limits,
+) -> Result<(), BodyError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = [0; 16 * 1024];
+
+    loop {
+        let wait = activity
+            .wait(direction, limits)
+            .map_err(|cause| BodyError::Read(Stop::Timeout(cause)))?;
+        let n = match time::timeout(wait, reader.read(&mut buffer)).await {
+            // Woken up to re-check the limits
+            Err(_) => continue,
+            Ok(Ok(0)) => break,
+            Ok(Ok(n)) => n,
+            Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Ok(Err(e)) => return Err(BodyError::Read(Stop::Io(e))),
+        };
+        write_within(writer, &buffer[..n], limits.idle)
+            .await
+            .map_err(BodyError::Write)?;
+        summary.record(&buffer[..n]);
+        activity.touch(direction);
+    }
+
+    // The peer may already be gone; nothing left to propagate then
+    let _ = writer.shutdown().await;
+    Ok(())
+}
+
+async fn write_within<W>(writer: &mut W, data: &[u8], limit: Duration) -> Result<(), Stop>
+where
+    W: AsyncWrite + Unpin,
+{
+    match time::timeout(limit, writer.write_all(data)).await {
+        Ok(result) => result.map_err(Stop::Io),
+        Err(_) => Err(Stop::Timeout(TimeoutCause::Idle)),
+    }
+}
+
+/*
+ * Reads a message head into `head` until `state` finds it complete or
+ * invalid. Returns `HeadState::Partial` if the peer closed first or the head
+ * outgrew `MAX_HEAD`. Each read may wait up to `timeout`. When
+ * `request_timeout` is given, the deadline is set that long after the first
+ * byte arrives.
+ */
+pub async fn read_head<R>(
+    reader: &mut R,
+    head: &mut Vec<u8>,
+    state: fn(&[u8]) -> HeadState,
+    limits: &mut Limits,
+    (timeout, cause): (Duration, TimeoutCause),
+    request_timeout: Option<Duration>,
+) -> Result<HeadState, Stop>
+where
+    R: AsyncBufRead + Unpin,
+{
+    while head.len() < http::MAX_HEAD {
+        let available = limits.within(timeout, cause, reader.fill_buf()).await?;
+        if available.is_empty() {
+            return Ok(HeadState::Partial);
+        }
+        if head.is_empty() {
+            // Stray line breaks between messages are ignored
+            let blank = available
+                .iter()
+                .take_while(|&&byte| byte == b'\r' || byte == b'\n')
+                .count();
+            if blank > 0 {
+                reader.consume(blank);
+                continue;
+            }
+            if let Some(request_timeout) = request_timeout {
+                limits.deadline = Some(Instant::now() + request_timeout);
+            }
+        }
+
+        // Only consume what belongs to the head, leaving the body or the
+        // next request in the reader
+        let before = head.len();
+        let read = available.len();
+        head.extend_from_slice(available);
+        match state(head) {
+            HeadState::Partial => reader.consume(read),
+            HeadState::Invalid => return Ok(HeadState::Invalid),
+            HeadState::Complete => {
+                let length = head_length(head);
+                reader.consume(length - before);
+                head.truncate(length);
+                return Ok(HeadState::Complete);
+            }
+        }
+    }
+    Ok(HeadState::Partial)
+}
+
+/*
+ * Where the blank line ending a complete head is
+ */
+fn head_length(head: &[u8]) -> usize {
+    let mut offset = 0;
+    for line in head.split_inclusive(|&byte| byte == b'\n') {
+        offset += line.len();
+        if line.trim_ascii().is_empty() {
+            break;
+        }
+    }
+    offset
+}
+
+/*
+ * Copies one message body framed by `length` from `reader` to `writer`,
+ * passing chunked encoding through as it is. Each read and write may wait up
+ * to the idle timeout. Returns the number of body bytes copied.
+ */
+pub async fn copy_body<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    length: BodyLength,
+    limits: &Limits,
+) -> Result<u64, BodyError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match length {
+        BodyLength::Fixed(length) => copy_exact(reader, writer, Some(length), limits).await,
+        BodyLength::UntilClose => copy_exact(reader, writer, None, limits).await,
+        BodyLength::Chunked => {
+            let mut copied = 0;
+            loop {
+                let line = copy_line(reader, writer, limits).await?;
+                let size = chunk_size(&line).ok_or_else(|| invalid("malformed chunk size"))?;
+                if size == 0 {
+                    // Trailer fields up to the closing blank line
+                    while !copy_line(reader, writer, limits).await?.is_empty() {}
+                    return Ok(copied);
+                }
+                copied += copy_exact(reader, writer, Some(size), limits).await?;
+                if !copy_line(reader, writer, limits).await?.is_empty() {
+                    return Err(invalid("chunk longer than its size"));
+                }
+            }
+        }
+    }
+}
+
+/*
+ * The size at the start of a chunk line: hex digits and nothing else, up to
+ * the extensions. Anything looser is a line the backend might read
+ * differently.
+ */
+pub fn chunk_size(line: &[u8]) -> Option<u64> {
+    let end = line
+        .iter()
+        .position(|&byte| byte == b';')
+        .unwrap_or(line.len());
+    let digits = &line[..end];
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    u64::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()
+}
+
+/*
+ * Copies `length` bytes, or everything up to EOF when it is None
+ */
+async fn copy_exact<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    length: Option<u64>,
+    limits: &Limits,
+) -> Result<u64, BodyError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut copied = 0;
+    while length.is_none_or(|length| copied < length) {
+        let available = limits
+            .within(limits.idle, TimeoutCause::Idle, reader.fill_buf())
+            .await
+            .map_err(BodyError::Read)?;
+        if available.is_empty() {
+            if length.is_none() {
+                break;
+            }
+            return Err(BodyError::Read(Stop::Io(
+                io::ErrorKind::UnexpectedEof.into(),
+            )));
+        }
+        let n = match length {
+            Some(length) => available.len().min((length - copied) as usize),
+            None => available.len(),
+        };
+        limits
+            .within(
+                limits.idle,
+                TimeoutCause::Idle,
+                writer.write_all(&available[..n]),
+            )
+            .await
+            .map_err(BodyError::Write)?;
+        reader.consume(n);
+        copied += n as u64;
+    }
+    Ok(copied)
+}
+
+/*
+ * Copies one line of chunked framing and returns it without the line break.
+ * Other trailing whitespace is kept for the caller to reject.
+ */
+async fn copy_line<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    limits: &Limits,
+) -> Result<Vec<u8>, BodyError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut line = Vec::new();
+    limits
+        .within(
+            limits.idle,
+            TimeoutCause::Idle,
+            (&mut *reader).take(MAX_LINE).read_until(b'\n', &mut line),
+        )
+        .await
+        .map_err(BodyError::Read)?;
+    if !line.ends_with(b"\n") {
+        return Err(invalid("truncated or overlong chunk line"));
+    }
+    limits
+        .within(limits.idle, TimeoutCause::Idle, writer.write_all(&line))
+        .await
+        .map_err(BodyError::Write)?;
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(line)
+}
+
+fn invalid(message: &str) -> BodyError {
+    BodyError::Read(Stop::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        message,
+    )))
+}
+
+pub fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> Limits {
+        Limits {
+            idle: Duration::from_secs(1),
+            first_byte: Duration::from_secs(1),
+            deadline: None,
+        }
+    }
+
+    async fn copy(input: &[u8], length: BodyLength) -> (Result<u64, BodyError>, Vec<u8>) {
+        let mut reader = input;
+        let mut output = Vec::new();
+        let result = copy_body(&mut reader, &mut output, length, &limits()).await;
+        (result, output)
+    }
+
+    #[test]
+    fn chunk_size_is_bare_hex() {
+        assert_eq!(chunk_size(b"0"), Some(0));
+        assert_eq!(chunk_size(b"1a"), Some(26));
+        assert_eq!(chunk_size(b"FF;name=value"), Some(255));
+        assert_eq!(chunk_size(b"10;"), Some(16));
+    }
+
+    #[test]
+    fn chunk_size_rejects_anything_else() {
+        for line in [
+            &b""[..],
+            b";ext",
+            b"+1a",
+            b"-1",
+            b" 1a",
+            b"1a ",
+            b"1a ;ext",
+            b"0x1a",
+            b"1g",
+            b"11111111111111111",
+        ] {
+            assert_eq!(
+                chunk_size(line),
+                None,
+                "{:?}",
+                String::from_utf8_lossy(line)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn copies_fixed_length_and_leaves_the_rest() {
+        let mut reader = &b"helloGET / HTTP/1.1"[..];
+        let mut output = Vec::new();
+        let copied = copy_body(&mut reader, &mut output, BodyLength::Fixed(5), &limits())
+            .await
+            .unwrap();
+        assert_eq!(copied, 5);
+        assert_eq!(output, b"hello");
+        assert_eq!(reader, b"GET / HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn fixed_length_cut_short_fails() {
+        let (result, _) = copy(b"hel", BodyLength::Fixed(5)).await;
+        assert!(matches!(result, Err(BodyError::Read(_))));
+    }
+
+    #[tokio::test]
+    async fn copies_until_close() {
+        let (result, output) = copy(b"all of it", BodyLength::UntilClose).await;
+        assert_eq!(result.unwrap(), 9);
+        assert_eq!(output, b"all of it");
+    }
+
+    #[tokio::test]
+    async fn passes_chunked_framing_through() {
+        let body = b"5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: x\r\n\r\n";
+        let mut input = body.to_vec();
+        input.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        let mut reader = &input[..];
+        let mut output = Vec::new();
+        let copied = copy_body(&mut reader, &mut output, BodyLength::Chunked, &limits())
+            .await
+            .unwrap();
+        assert_eq!(copied, 11);
+        assert_eq!(output, body);
+        assert_eq!(reader, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn accepts_bare_line_feeds_in_chunked_framing() {
+        let (result, _) = copy(b"3\nabc\n0\n\n", BodyLength::Chunked).await;
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_chunks() {
+        for input in [
+            &b"+3\r\nabc\r\n0\r\n\r\n"[..],
+            b"3 \r\nabc\r\n0\r\n\r\n",
+            b"zz\r\n",
+            b"3\r\nabcd\r\n0\r\n\r\n",
+            b"3\r\nab",
+            b"3\r\nabc\r\n0\r\n",
+        ] {
+            let (result, _) = copy(input, BodyLength::Chunked).await;
+            assert!(
+                matches!(result, Err(BodyError::Read(_))),
+                "{:?}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
+    /*
+     * A connected pair of sockets: the one the relay holds and its peer
+     */
+    async fn connected() -> (Stream, tokio::net::TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let near = tokio::net::TcpStream::connect(listener.local_addr().unwrap());
+        let (near, far) = tokio::join!(near, listener.accept());
+        (Stream::Plain(near.unwrap()), far.unwrap().0)
+    }
+
+    async fn relay_until_reset(reset_client: bool) -> Result<RelaySummary, RelayError> {
+        let (mut client, client_peer) = connected().await;
+        let (backend, backend_peer) = connected().await;
+        let peer = if reset_client {
+            client_peer
+        } else {
+            backend_peer
+        };
+        peer.set_zero_linger().unwrap();
+        drop(peer);
+        let preamble = Preamble {
+            request_bytes: 0,
+            response: Vec::new(),
+        };
+        relay(&mut client, backend, preamble, limits()).await
+    }
+
+    #[tokio::test]
+    async fn relay_blames_the_side_that_broke() {
+        assert!(matches!(
+            relay_until_reset(true).await,
+            Err(RelayError::Client(_))
+        ));
+        assert!(matches!(
+            relay_until_reset(false).await,
+            Err(RelayError::Backend(_))
+        ));
+    }
+}
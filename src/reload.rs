@@ -0,0 +1,72 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    thread,
+    time::SystemTime,
+};
+
+use signal_hook::{
+    consts::{SIGHUP, SIGUSR1},
+    iterator::Signals,
+};
+
+use crate::balancer::LoadBalancer;
+
+/*
+ * Starts the threads that reload `path` on SIGHUP and, when
+ * `reload.watch_interval_ms` is set, whenever the file is modified. SIGUSR1
+ * reopens the access log after rotation.
+ */
+pub fn spawn(balancer: &LoadBalancer, path: PathBuf) -> io::Result<()> {
+    let mut signals = Signals::new([SIGHUP, SIGUSR1])?;
+    {
+        let balancer = balancer.clone();
+        let path = path.clone();
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                if signal == SIGUSR1 {
+                    match balancer.reopen_access_log() {
+                        Ok(()) => eprintln!("Received SIGUSR1, reopened the access log"),
+                        Err(e) => eprintln!("Keeping the old access log, reopening failed: {}", e),
+                    }
+                    continue;
+                }
+                eprintln!("Received SIGHUP, reloading {}", path.display());
+                apply(&balancer, &path);
+            }
+        });
+    }
+
+    if balancer.config().reload.watch_interval.is_some() {
+        let balancer = balancer.clone();
+        thread::spawn(move || watch(&balancer, &path));
+    }
+
+    Ok(())
+}
+
+fn watch(balancer: &LoadBalancer, path: &Path) {
+    let mut last_modified = modified(path);
+    // The interval is re-read every round so a reload can change or disable it.
+    while let Some(interval) = balancer.config().reload.watch_interval {
+        thread::sleep(interval);
+
+        let modified = modified(path);
+        if modified.is_some() && modified != last_modified {
+            last_modified = modified;
+            eprintln!("{} changed, reloading", path.display());
+            apply(balancer, path);
+        }
+    }
+}
+
+fn apply(balancer: &LoadBalancer, path: &Path) {
+    match balancer.reload(path) {
+        Ok(()) => eprintln!("Reloaded {}", path.display()),
+        Err(e) => eprintln!("Keeping previous config, reload failed: {}", e),
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
@@ -0,0 +1,127 @@
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+use crate::config::RetryConfig;
+
+/*
+ * Limits retries to a share of the requests in flight so that a struggling
+ * pool is not buried under a retry storm
+ */
+#[derive(Debug, Default)]
+pub struct RetryBudget {
+    requests: Arc<AtomicUsize>,
+    retries: Arc<AtomicUsize>,
+    /*
+     * Retries granted since the pool was created
+     */
+    retried: AtomicU64,
+}
+
+/*
+ * Decrements its counter when dropped
+ */
+pub struct InFlight {
+    counter: Arc<AtomicUsize>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl RetryBudget {
+    pub fn start_request(&self) -> InFlight {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        InFlight {
+            counter: Arc::clone(&self.requests),
+        }
+    }
+
+    /*
+     * Claims room for one retry, or None when the budget is spent
+     */
+    pub fn try_retry(&self, config: &RetryConfig) -> Option<InFlight> {
+        let requests = self.requests.load(Ordering::Relaxed);
+        let allowed = (requests * usize::from(config.budget_percent) / 100)
+            .max(config.min_concurrent_retries as usize);
+
+        self.retries
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |retries| {
+                (retries < allowed).then_some(retries + 1)
+            })
+            .ok()?;
+        self.retried.fetch_add(1, Ordering::Relaxed);
+        Some(InFlight {
+            counter: Arc::clone(&self.retries),
+        })
+    }
+
+    pub fn active_retries(&self) -> usize {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    pub fn retried(&self) -> u64 {
+        self.retried.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(budget_percent: u8, min_concurrent_retries: u32) -> RetryConfig {
+        toml::from_str(&format!(
+            "budget_percent = {}\nmin_concurrent_retries = {}",
+            budget_percent, min_concurrent_retries
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn allows_the_minimum_without_traffic() {
+        let budget = RetryBudget::default();
+        let config = config(20, 2);
+        let first = budget.try_retry(&config);
+        let second = budget.try_retry(&config);
+        assert!(first.is_some() && second.is_some());
+        assert!(budget.try_retry(&config).is_none());
+        assert_eq!(budget.active_retries(), 2);
+    }
+
+    #[test]
+    fn allows_a_share_of_the_requests_in_flight() {
+        let budget = RetryBudget::default();
+        let config = config(20, 1);
+        let _requests: Vec<InFlight> = (0..20).map(|_| budget.start_request()).collect();
+        let retries: Vec<InFlight> = (0..10).map_while(|_| budget.try_retry(&config)).collect();
+        assert_eq!(retries.len(), 4);
+        assert_eq!(budget.retried(), 4);
+    }
+
+    #[test]
+    fn finished_requests_shrink_the_budget() {
+        let budget = RetryBudget::default();
+        let config = config(50, 0);
+        let requests: Vec<InFlight> = (0..4).map(|_| budget.start_request()).collect();
+        let retry = budget.try_retry(&config);
+        assert!(retry.is_some());
+        drop(requests);
+        assert!(budget.try_retry(&config).is_none());
+    }
+
+    #[test]
+    fn dropping_a_retry_frees_its_slot() {
+        let budget = RetryBudget::default();
+        let config = config(20, 1);
+        let retry = budget.try_retry(&config).unwrap();
+        assert!(budget.try_retry(&config).is_none());
+
+        drop(retry);
+        assert_eq!(budget.active_retries(), 0);
+        let _retry = budget.try_retry(&config).unwrap();
+        assert_eq!(budget.retried(), 2);
+    }
+}
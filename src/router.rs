@@ -0,0 +1,269 @@
+use std::borrow::Cow;
+
+use crate::{
+    config::{RouteConfig, SniRouteConfig},
+    http::RequestHead,
+};
+
+/*
+ * Picks the pool for `request`: the first route it matches, or None when it
+ * matches none
+ */
+pub fn route<'a>(routes: &'a [RouteConfig], request: &RequestHead) -> Option<&'a str> {
+    routes
+        .iter()
+        .find(|route| matches(route, request))
+        .map(|route| route.pool.get_ref().as_str())
+}
+
+fn matches(route: &RouteConfig, request: &RequestHead) -> bool {
+    let path = request
+        .path
+        .split_once('?')
+        .map_or(request.path.as_str(), |(path, _)| path);
+    let path = normalize(path);
+    let path = path.as_ref();
+
+    if let Some(pattern) = &route.host {
+        if !request
+            .header("Host")
+            .map(host_name)
+            .is_some_and(|host| pattern.matches(host))
+        {
+            return false;
+        }
+    }
+    if let Some(prefix) = &route.path_prefix {
+        if !has_prefix(path, prefix.get_ref()) {
+            return false;
+        }
+    }
+    if let Some(regex) = &route.path_regex {
+        if !regex.is_match(path) {
+            return false;
+        }
+    }
+    if let Some(methods) = &route.methods {
+        if !methods
+            .iter()
+            .any(|method| method.eq_ignore_ascii_case(&request.method))
+        {
+            return false;
+        }
+    }
+    route.headers.iter().all(|(name, value)| {
+        request
+            .header(name)
+            .is_some_and(|actual| actual.trim() == value)
+    })
+}
+
+/*
+ * The path as the backend will resolve it, so that `/api/../admin` or
+ * `/api/%2e%2e/admin` cannot slip past a route for `/admin` by matching
+ * one for `/api`. Percent-escapes of unreserved characters are decoded
+ * (RFC 3986 6.2.2.2) and dot-segments removed (5.2.4); other escapes, such
+ * as `%2F`, stay as they are.
+ */
+fn normalize(path: &str) -> Cow<'_, str> {
+    let decoded = decode_unreserved(path);
+    if !decoded
+        .split('/')
+        .any(|segment| segment == "." || segment == "..")
+    {
+        return decoded;
+    }
+
+    let mut segments: Vec<&str> = Vec::new();
+    let mut rest = decoded
+        .strip_prefix('/')
+        .unwrap_or(&decoded)
+        .split('/')
+        .peekable();
+    while let Some(segment) = rest.next() {
+        let last = rest.peek().is_none();
+        match segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => {
+                segments.push(segment);
+                continue;
+            }
+        }
+        // A trailing dot-segment still names a directory
+        if last {
+            segments.push("");
+        }
+    }
+    Cow::Owned(format!("/{}", segments.join("/")))
+}
+
+fn decode_unreserved(path: &str) -> Cow<'_, str> {
+    if !path.contains('%') {
+        return Cow::Borrowed(path);
+    }
+    let mut decoded = String::with_capacity(path.len());
+    let mut rest = path;
+    while let Some(at) = rest.find('%') {
+        decoded.push_str(&rest[..at]);
+        let escaped = rest
+            .get(at + 1..at + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .filter(|&byte| byte.is_ascii_alphanumeric() || b"-._~".contains(&byte));
+        match escaped {
+            Some(byte) => {
+                decoded.push(char::from(byte));
+                rest = &rest[at + 3..];
+            }
+            None => {
+                decoded.push('%');
+                rest = &rest[at + 1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    Cow::Owned(decoded)
+}
+
+/*
+ * Whether `path` lies under `prefix`, only ever splitting at a `/`
+ */
+fn has_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/*
+ * Strips the port from a Host header value, including around an IPv6
+ * literal such as `[::1]:8080`
+ */
+fn host_name(host: &str) -> &str {
+    let host = host.trim();
+    if let Some(literal) = host.strip_prefix('[') {
+        return literal.split_once(']').map_or(host, |(address, _)| address);
+    }
+    host.rsplit_once(':').map_or(host, |(name, _)| name)
+}
+
+/*
+ * Picks the pool for a passthrough connection by the server name of its
+ * ClientHello
+ */
+pub fn route_sni<'a>(routes: &'a [SniRouteConfig], server_name: &str) -> Option<&'a str> {
+    routes
+        .iter()
+        .find(|route| route.host.matches(server_name))
+        .map(|route| route.pool.get_ref().as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str, host: &str) -> RequestHead {
+        let raw = format!("{method} {path} HTTP/1.1\r\nHost: {host}\r\nX-Canary: yes\r\n\r\n");
+        RequestHead::parse(raw.as_bytes()).unwrap()
+    }
+
+    fn routes(toml: &str) -> Vec<RouteConfig> {
+        #[derive(serde::Deserialize)]
+        struct Routes {
+            route: Vec<RouteConfig>,
+        }
+        toml::from_str::<Routes>(toml).unwrap().route
+    }
+
+    #[test]
+    fn prefixes_only_split_at_a_slash() {
+        assert!(has_prefix("/api", "/api"));
+        assert!(has_prefix("/api/users", "/api"));
+        assert!(!has_prefix("/apis", "/api"));
+        assert!(has_prefix("/api/users", "/api/"));
+        assert!(!has_prefix("/api", "/api/"));
+        assert!(has_prefix("/anything", "/"));
+    }
+
+    #[test]
+    fn host_names_lose_their_port() {
+        assert_eq!(host_name("example.com"), "example.com");
+        assert_eq!(host_name("example.com:80"), "example.com");
+        assert_eq!(host_name(" example.com:8080 "), "example.com");
+        assert_eq!(host_name("[::1]:8080"), "::1");
+        assert_eq!(host_name("[::1]"), "::1");
+    }
+
+    #[test]
+    fn wildcard_hosts_match_subdomains_only() {
+        let routes = routes(
+            r#"
+            [[route]]
+            host = "*.example.com"
+            pool = "sub"
+
+            [[route]]
+            host = "example.com"
+            pool = "apex"
+            "#,
+        );
+        let pick = |host| route(&routes, &request("GET", "/", host));
+        assert_eq!(pick("www.example.com"), Some("sub"));
+        assert_eq!(pick("a.b.example.com:8443"), Some("sub"));
+        assert_eq!(pick("example.com"), Some("apex"));
+        assert_eq!(pick("badexample.com"), None);
+    }
+
+    #[test]
+    fn paths_are_normalized_before_matching() {
+        assert_eq!(normalize("/api/users"), "/api/users");
+        assert_eq!(normalize("/api/../admin"), "/admin");
+        assert_eq!(normalize("/api/%2e%2E/admin"), "/admin");
+        assert_eq!(normalize("/api/./v1/."), "/api/v1/");
+        assert_eq!(normalize("/../../etc"), "/etc");
+        assert_eq!(normalize("/%7Euser/%41"), "/~user/A");
+        assert_eq!(normalize("/a%2Fb/%zz/%"), "/a%2Fb/%zz/%");
+
+        let routes = routes(
+            r#"
+            [[route]]
+            path_prefix = "/api"
+            pool = "api"
+
+            [[route]]
+            path_prefix = "/admin"
+            pool = "admin"
+            "#,
+        );
+        let pick = |path| route(&routes, &request("GET", path, "example.com"));
+        assert_eq!(pick("/api/users?next=/admin"), Some("api"));
+        assert_eq!(pick("/api/../admin"), Some("admin"));
+        assert_eq!(pick("/api/%2e%2e/admin"), Some("admin"));
+        assert_eq!(pick("/apis"), None);
+    }
+
+    #[test]
+    fn methods_and_headers_narrow_a_route() {
+        let routes = routes(
+            r#"
+            [[route]]
+            methods = ["post"]
+            pool = "writes"
+
+            [[route]]
+            headers = { X-Canary = "yes" }
+            pool = "canary"
+            "#,
+        );
+        assert_eq!(
+            route(&routes, &request("POST", "/", "example.com")),
+            Some("writes")
+        );
+        assert_eq!(
+            route(&routes, &request("GET", "/", "example.com")),
+            Some("canary")
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    io, process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use tokio::sync::watch;
+
+use crate::balancer::LoadBalancer;
+
+/*
+ * Tells everything serving clients that the balancer is shutting down, and
+ * counts the client connections it is waiting for
+ */
+pub struct Shutdown {
+    requested: watch::Sender<bool>,
+    open: AtomicUsize,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            requested: watch::Sender::new(false),
+            open: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Shutdown {
+    /*
+     * Returns false when a shutdown was already under way
+     */
+    pub fn request(&self) -> bool {
+        !self.requested.send_replace(true)
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.requested.borrow()
+    }
+
+    /*
+     * Resolves once a shutdown is requested, right away if it already was
+     */
+    pub async fn requested(&self) {
+        let mut receiver = self.requested.subscribe();
+        let _ = receiver.wait_for(|requested| *requested).await;
+    }
+
+    pub fn open_connections(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /*
+     * Counts a client connection as open until the guard is dropped
+     */
+    pub fn track(self: &Arc<Self>) -> OpenConnection {
+        self.open.fetch_add(1, Ordering::Relaxed);
+        OpenConnection {
+            shutdown: Arc::clone(self),
+        }
+    }
+}
+
+pub struct OpenConnection {
+    shutdown: Arc<Shutdown>,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.shutdown.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/*
+ * Starts the thread that shuts the balancer down gracefully on SIGTERM or
+ * SIGINT. A second signal exits at once without waiting for connections.
+ */
+pub fn spawn(balancer: &LoadBalancer) -> io::Result<()> {
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    let balancer = balancer.clone();
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            let name = if signal == SIGTERM {
+                "SIGTERM"
+            } else {
+                "SIGINT"
+            };
+            if balancer.shutdown() {
+                eprintln!("Received {}, draining connections", name);
+            } else {
+                eprintln!("Received {} again, exiting now", name);
+                process::exit(1);
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,233 @@
+/*
+ * Upper bound on the bytes read while looking for a complete ClientHello
+ */
+pub const MAX_HELLO: usize = 32 * 1024;
+
+const HANDSHAKE_RECORD: u8 = 22;
+const CLIENT_HELLO: u8 = 1;
+const SERVER_NAME_EXTENSION: u16 = 0;
+const HOST_NAME: u8 = 0;
+
+/*
+ * How far the bytes a client sent first get towards a TLS ClientHello
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientHello {
+    /*
+     * The whole ClientHello arrived; carries the host name the client asked
+     * for, if any
+     */
+    Complete(Option<String>),
+    Partial,
+    Invalid,
+}
+
+/*
+ * Parses the ClientHello at the start of `buffer`, which may be spread over
+ * several handshake records. Nothing is consumed; the caller forwards the
+ * bytes as they are.
+ */
+pub fn parse(buffer: &[u8]) -> ClientHello {
+    let mut handshake = Vec::new();
+    let mut records = buffer;
+    loop {
+        let Some(header) = records.get(..5) else {
+            return ClientHello::Partial;
+        };
+        if header[0] != HANDSHAKE_RECORD || header[1] != 3 {
+            return ClientHello::Invalid;
+        }
+        let length = usize::from(u16::from_be_bytes([header[3], header[4]]));
+        let Some(fragment) = records.get(5..5 + length) else {
+            return ClientHello::Partial;
+        };
+        handshake.extend_from_slice(fragment);
+        records = &records[5 + length..];
+
+        if handshake.len() >= 4 {
+            if handshake[0] != CLIENT_HELLO {
+                return ClientHello::Invalid;
+            }
+            let length = usize::from(handshake[1]) << 16
+                | usize::from(handshake[2]) << 8
+                | usize::from(handshake[3]);
+            if handshake.len() >= 4 + length {
+                return match server_name(&handshake[4..4 + length]) {
+                    Some(name) => ClientHello::Complete(name),
+                    None => ClientHello::Invalid,
+                };
+            }
+        }
+    }
+}
+
+/*
+ * Finds the host name in the body of a ClientHello. Returns None when the
+ * body is malformed and Some(None) when it has no server_name extension.
+ */
+fn server_name(body: &[u8]) -> Option<Option<String>> {
+    let mut reader = Reader(body);
+    reader.take(2 + 32)?; // version and random
+    reader.vector(1)?; // session id
+    reader.vector(2)?; // cipher suites
+    reader.vector(1)?; // compression methods
+    if reader.0.is_empty() {
+        return Some(None);
+    }
+
+    let mut extensions = Reader(reader.vector(2)?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let data = extensions.vector(2)?;
+        if kind != SERVER_NAME_EXTENSION {
+            continue;
+        }
+        let mut names = Reader(Reader(data).vector(2)?);
+        while !names.0.is_empty() {
+            let name_type = names.take(1)?[0];
+            let name = names.vector(2)?;
+            if name_type == HOST_NAME {
+                let name = std::str::from_utf8(name).ok()?;
+                return Some(Some(name.to_ascii_lowercase()));
+            }
+        }
+        return Some(None);
+    }
+    Some(None)
+}
+
+/*
+ * Walks the length-prefixed fields of a handshake message
+ */
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        if self.0.len() < count {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /*
+     * A field preceded by its length in `width` bytes
+     */
+    fn vector(&mut self, width: usize) -> Option<&'a [u8]> {
+        let length = self
+            .take(width)?
+            .iter()
+            .fold(0, |length, &byte| length << 8 | usize::from(byte));
+        self.take(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vector(width: usize, data: &[u8]) -> Vec<u8> {
+        let length = data.len().to_be_bytes();
+        [&length[length.len() - width..], data].concat()
+    }
+
+    /*
+     * A ClientHello body with the given extensions, each as (type, data)
+     */
+    fn hello(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        body.extend(vector(1, &[]));
+        body.extend(vector(2, &[0x13, 0x01]));
+        body.extend(vector(1, &[0]));
+        let extensions: Vec<u8> = extensions
+            .iter()
+            .flat_map(|(kind, data)| [&kind.to_be_bytes()[..], &vector(2, data)].concat())
+            .collect();
+        body.extend(vector(2, &extensions));
+
+        let mut handshake = vec![CLIENT_HELLO];
+        handshake.extend(vector(3, &body));
+        handshake
+    }
+
+    fn server_name_extension(name: &str) -> (u16, Vec<u8>) {
+        let entry = [&[HOST_NAME][..], &vector(2, name.as_bytes())].concat();
+        (SERVER_NAME_EXTENSION, vector(2, &entry))
+    }
+
+    /*
+     * Splits a handshake message into records of at most `size` bytes
+     */
+    fn records(handshake: &[u8], size: usize) -> Vec<u8> {
+        handshake
+            .chunks(size)
+            .flat_map(|fragment| [&[HANDSHAKE_RECORD, 3, 1][..], &vector(2, fragment)].concat())
+            .collect()
+    }
+
+    #[test]
+    fn finds_the_server_name() {
+        let handshake = hello(&[
+            (10, vec![0, 2, 0, 29]),
+            server_name_extension("Example.COM"),
+        ]);
+        assert_eq!(
+            parse(&records(&handshake, 1 << 14)),
+            ClientHello::Complete(Some("example.com".to_string()))
+        );
+    }
+
+    #[test]
+    fn reassembles_hellos_split_over_records() {
+        let handshake = hello(&[server_name_extension("a.example")]);
+        let buffer = records(&handshake, 7);
+        assert_eq!(
+            parse(&buffer),
+            ClientHello::Complete(Some("a.example".to_string()))
+        );
+        for end in [0, 4, 5, 20, buffer.len() - 1] {
+            assert_eq!(parse(&buffer[..end]), ClientHello::Partial, "{}", end);
+        }
+    }
+
+    #[test]
+    fn hello_without_server_name() {
+        assert_eq!(
+            parse(&records(&hello(&[(10, vec![0, 2, 0, 29])]), 1 << 14)),
+            ClientHello::Complete(None)
+        );
+        assert_eq!(
+            parse(&records(&hello(&[]), 1 << 14)),
+            ClientHello::Complete(None)
+        );
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_hello() {
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n\r\n"), ClientHello::Invalid);
+        assert_eq!(parse(&[21, 3, 3, 0, 2, 2, 40]), ClientHello::Invalid);
+
+        let mut server_hello = hello(&[]);
+        server_hello[0] = 2;
+        assert_eq!(
+            parse(&records(&server_hello, 1 << 14)),
+            ClientHello::Invalid
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_extensions() {
+        let mut handshake = hello(&[server_name_extension("a.example")]);
+        // The extension claims one byte more than the extension list holds
+        let at = handshake.len() - "a.example".len() - 6;
+        handshake[at] += 1;
+        assert_eq!(parse(&records(&handshake, 1 << 14)), ClientHello::Invalid);
+    }
+}
@@ -0,0 +1,403 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, OnceLock, RwLock,
+    },
+    time::Duration,
+};
+
+use crate::{backend::Backend, config::Algorithm};
+
+/*
+ * What a strategy knows about the connection it is placing
+ */
+#[derive(Clone, Debug)]
+pub struct RequestContext {
+    pub client_addr: SocketAddr,
+}
+
+/*
+ * How a connection handed out by `select` ended
+ */
+#[derive(Clone, Debug)]
+pub struct Outcome {
+    pub success: bool,
+    /*
+     * Status of the backend's response, when it answered with HTTP
+     */
+    pub status: Option<u16>,
+    pub duration: Duration,
+}
+
+/*
+ * Chooses a backend for each connection. Implementations are shared between
+ * all connections of a pool, which are served from several worker threads,
+ * so any state they keep must be synchronised. The methods run on those
+ * workers and must not block. `backends` is never empty when `select` is
+ * called.
+ */
+pub trait BalancingStrategy: Send + Sync {
+    /*
+     * Returns the index into `backends` of the backend to use
+     */
+    fn select(&self, context: &RequestContext, backends: &[Arc<Backend>]) -> Option<usize>;
+
+    /*
+     * Called once the connection to the selected backend is established
+     */
+    fn on_connect(&self, _backend: &Backend) {}
+
+    /*
+     * Called when the connection ends, or fails to connect at all
+     */
+    fn on_complete(&self, _backend: &Backend, _outcome: &Outcome) {}
+}
+
+pub type StrategyFactory = fn() -> Box<dyn BalancingStrategy>;
+
+fn registry() -> &'static RwLock<HashMap<String, StrategyFactory>> {
+    static REGISTRY: OnceLock<RwLock<HashMap<String, StrategyFactory>>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+/*
+ * Makes a custom strategy available to pools as `algorithm = "<name>"`.
+ * Must be called before the config that uses it is loaded.
+ */
+pub fn register(name: &str, factory: StrategyFactory) {
+    registry()
+        .write()
+        .unwrap()
+        .insert(name.to_string(), factory);
+}
+
+pub fn is_registered(name: &str) -> bool {
+    registry().read().unwrap().contains_key(name)
+}
+
+/*
+ * Instantiates the strategy for a pool. Custom names are checked against the
+ * registry when the config is parsed, so a miss here means the registration
+ * was removed in between.
+ */
+pub fn build(algorithm: &Algorithm) -> Box<dyn BalancingStrategy> {
+    match algorithm {
+        Algorithm::RoundRobin => Box::new(RoundRobin::default()),
+        Algorithm::WeightedRoundRobin => Box::new(SmoothWeighted),
+        Algorithm::LeastConnections => Box::new(LeastConnections::new(false)),
+        Algorithm::WeightedLeastConnections => Box::new(LeastConnections::new(true)),
+        Algorithm::Random => Box::new(Random),
+        Algorithm::Hash => Box::new(ClientHash),
+        Algorithm::Custom(name) => match registry().read().unwrap().get(name) {
+            Some(factory) => factory(),
+            None => {
+                eprintln!("Strategy {} is not registered, using round_robin", name);
+                Box::new(RoundRobin::default())
+            }
+        },
+    }
+}
+
+#[derive(Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl BalancingStrategy for RoundRobin {
+    fn select(&self, _context: &RequestContext, backends: &[Arc<Backend>]) -> Option<usize> {
+        Some(self.next.fetch_add(1, Ordering::Relaxed) % backends.len())
+    }
+}
+
+pub struct Random;
+
+impl BalancingStrategy for Random {
+    fn select(&self, _context: &RequestContext, backends: &[Arc<Backend>]) -> Option<usize> {
+        Some(fastrand::usize(..backends.len()))
+    }
+}
+
+/*
+ * Smooth weighted round robin as used by nginx: every pick raises each
+ * backend's score by its weight, hands out the highest scorer and lowers it by
+ * the total. Weights 5/1/1 give `a a b a c a a` rather than five in a row.
+ * The scores are kept on the backends themselves, so backends skipped for a
+ * pick keep theirs and concurrent picks need no lock; racing picks may
+ * interleave slightly differently, but every raise is matched by a
+ * lowering and the shares stay proportional to the weights.
+ */
+#[derive(Default)]
+pub struct SmoothWeighted;
+
+impl BalancingStrategy for SmoothWeighted {
+    fn select(&self, _context: &RequestContext, backends: &[Arc<Backend>]) -> Option<usize> {
+        let mut total = 0;
+        let mut best: Option<(usize, i64)> = None;
+        for (index, backend) in backends.iter().enumerate() {
+            let weight = i64::from(backend.weight());
+            total += weight;
+
+            let current = backend.score().fetch_add(weight, Ordering::Relaxed) + weight;
+            if best.is_none_or(|(_, score)| current > score) {
+                best = Some((index, current));
+            }
+        }
+
+        let (index, _) = best?;
+        backends[index].score().fetch_sub(total, Ordering::Relaxed);
+        Some(index)
+    }
+}
+
+/*
+ * Picks the backend with the fewest active connections, or with weights the
+ * lowest connections-per-weight ratio. The scan starts one further along on
+ * every call so that ties rotate instead of always landing on the first
+ * backend.
+ */
+pub struct LeastConnections {
+    weighted: bool,
+    next: AtomicUsize,
+}
+
+impl LeastConnections {
+    pub fn new(weighted: bool) -> Self {
+        LeastConnections {
+            weighted,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl BalancingStrategy for LeastConnections {
+    fn select(&self, _context: &RequestContext, backends: &[Arc<Backend>]) -> Option<usize> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..backends.len())
+            .map(|offset| (start + offset) % backends.len())
+            .min_by(|&a, &b| {
+                let (a, b) = (&backends[a], &backends[b]);
+                let a_active = a.active_connections() as u64;
+                let b_active = b.active_connections() as u64;
+                if self.weighted {
+                    (a_active * u64::from(b.weight())).cmp(&(b_active * u64::from(a.weight())))
+                } else {
+                    a_active.cmp(&b_active)
+                }
+            })
+    }
+}
+
+/*
+ * Weighted rendezvous hashing on the client IP: the same client keeps landing
+ * on the same backend, and adding or removing a backend only moves the
+ * clients that hash to it.
+ */
+pub struct ClientHash;
+
+impl BalancingStrategy for ClientHash {
+    fn select(&self, context: &RequestContext, backends: &[Arc<Backend>]) -> Option<usize> {
+        let score = |backend: &Backend| {
+            let mut hasher = DefaultHasher::new();
+            context.client_addr.ip().hash(&mut hasher);
+            backend.address().hash(&mut hasher);
+            // Map the hash into (0, 1) and scale by weight
+            let unit = (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64;
+            let unit = unit.max(f64::MIN_POSITIVE);
+            f64::from(backend.weight()) / -unit.ln()
+        };
+
+        (0..backends.len()).max_by(|&a, &b| score(&backends[a]).total_cmp(&score(&backends[b])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+    use crate::{backend::Pool, config::PoolConfig};
+
+    fn backends(weights: &[u32]) -> Vec<Arc<Backend>> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(i, &weight)| Arc::new(Backend::new(format!("10.0.0.{}:80", i + 1), weight)))
+            .collect()
+    }
+
+    fn context() -> RequestContext {
+        RequestContext {
+            client_addr: "192.0.2.1:40000".parse().unwrap(),
+        }
+    }
+
+    /*
+     * The addresses picked from `candidates` over `count` picks
+     */
+    fn picks(
+        strategy: &dyn BalancingStrategy,
+        candidates: &[Arc<Backend>],
+        count: usize,
+    ) -> Vec<String> {
+        (0..count)
+            .map(|_| {
+                let index = strategy.select(&context(), candidates).unwrap();
+                candidates[index].address().to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn smooth_weighted_interleaves_like_nginx() {
+        let backends = backends(&[5, 1, 1]);
+        let [a, b, c] = ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"];
+        assert_eq!(
+            picks(&SmoothWeighted, &backends, 14),
+            [a, a, b, a, c, a, a, a, a, b, a, c, a, a]
+        );
+    }
+
+    #[test]
+    fn smooth_weighted_skipping_a_backend_leaves_the_others_in_step() {
+        let backends = backends(&[5, 1, 1]);
+        let [a, b, c] = ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"];
+        let strategy = SmoothWeighted;
+
+        // The third backend is ejected for a while, so the pool leaves it out
+        let without_c = &backends[..2];
+        assert_eq!(picks(&strategy, without_c, 6), [a, a, a, b, a, a]);
+        assert_eq!(backends[2].score().load(Ordering::Relaxed), 0);
+
+        // Back in rotation, the full cycle is exactly as if it never left
+        assert_eq!(picks(&strategy, &backends, 7), [a, a, b, a, c, a, a]);
+        for backend in &backends {
+            assert_eq!(backend.score().load(Ordering::Relaxed), 0);
+        }
+    }
+
+    fn pool(algorithm: &str, weights: &[u32]) -> Pool {
+        let mut source = format!("algorithm = \"{}\"\n", algorithm);
+        for (i, weight) in weights.iter().enumerate() {
+            source += &format!(
+                "[[backend]]\naddress = \"10.0.0.{}:80\"\nweight = {}\n",
+                i + 1,
+                weight
+            );
+        }
+        let config: PoolConfig = toml::from_str(&source).unwrap();
+        Pool::new("test", &config)
+    }
+
+    #[test]
+    fn least_connections_rotates_ties() {
+        let backends = backends(&[1, 1, 1]);
+        let strategy = LeastConnections::new(false);
+        let [a, b, c] = ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"];
+        assert_eq!(picks(&strategy, &backends, 4), [a, b, c, a]);
+    }
+
+    #[test]
+    fn least_connections_spreads_open_connections() {
+        let pool = pool("least_connections", &[1, 1, 1]);
+        let held: Vec<_> = (0..6)
+            .map(|_| pool.select(&context(), &[]).unwrap())
+            .collect();
+        for backend in pool.backends() {
+            assert_eq!(backend.active_connections(), 2, "{}", backend.address());
+        }
+
+        // Closing connections on one backend sends the next picks there
+        let freed = "10.0.0.2:80";
+        let held: Vec<_> = held
+            .into_iter()
+            .filter(|(backend, _)| backend.address() != freed)
+            .collect();
+        for _ in 0..2 {
+            let (backend, _guard) = pool.select(&context(), &[]).unwrap();
+            assert_eq!(backend.address(), freed);
+        }
+        drop(held);
+    }
+
+    #[test]
+    fn weighted_least_connections_follows_weights() {
+        let pool = pool("weighted_least_connections", &[3, 1]);
+        let _held: Vec<_> = (0..8)
+            .map(|_| pool.select(&context(), &[]).unwrap())
+            .collect();
+        let active: Vec<usize> = pool
+            .backends()
+            .iter()
+            .map(|backend| backend.active_connections())
+            .collect();
+        assert_eq!(active, [6, 2]);
+    }
+
+    #[test]
+    fn client_hash_is_stable_and_only_moves_a_removed_backends_clients() {
+        let backends = backends(&[1, 1, 1, 1]);
+        let strategy = ClientHash;
+        let pick = |client: u32, candidates: &[Arc<Backend>]| {
+            let context = RequestContext {
+                client_addr: (IpAddr::V4(Ipv4Addr::from(client)), 40000).into(),
+            };
+            let index = strategy.select(&context, candidates).unwrap();
+            candidates[index].address().to_string()
+        };
+
+        let clients = 0x0a00_0000..0x0a00_0000 + 400;
+        let before: Vec<String> = clients
+            .clone()
+            .map(|client| pick(client, &backends))
+            .collect();
+        for (client, address) in clients.clone().zip(&before) {
+            assert_eq!(&pick(client, &backends), address);
+        }
+        for backend in &backends {
+            assert!(before.iter().any(|address| address == backend.address()));
+        }
+
+        let removed = backends[1].address();
+        let remaining: Vec<Arc<Backend>> = backends
+            .iter()
+            .filter(|backend| backend.address() != removed)
+            .cloned()
+            .collect();
+        for (client, address) in clients.zip(&before) {
+            let after = pick(client, &remaining);
+            if address == removed {
+                assert_ne!(after, removed);
+            } else {
+                assert_eq!(&after, address);
+            }
+        }
+    }
+
+    #[test]
+    fn custom_strategies_are_registered_by_name() {
+        assert!(!is_registered("test_first"));
+        assert!(toml::from_str::<PoolConfig>("algorithm = \"test_first\"").is_err());
+
+        struct First;
+        impl BalancingStrategy for First {
+            fn select(
+                &self,
+                _context: &RequestContext,
+                _backends: &[Arc<Backend>],
+            ) -> Option<usize> {
+                Some(0)
+            }
+        }
+        register("test_first", || Box::new(First));
+        assert!(is_registered("test_first"));
+
+        let pool = pool("test_first", &[1, 1]);
+        for _ in 0..3 {
+            let (backend, _guard) = pool.select(&context(), &[]).unwrap();
+            assert_eq!(backend.address(), "10.0.0.1:80");
+        }
+    }
+}
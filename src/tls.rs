@@ -0,0 +1,326 @@
+use std::{
+    fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use arc_swap::ArcSwap;
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        ResolvesClientCert,
+    },
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    version, ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+    SupportedCipherSuite, SupportedProtocolVersion,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::{CertificateConfig, TlsConfig, TlsVersion, UpstreamTlsConfig};
+
+/*
+ * How often certificate files are checked for changes
+ */
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/*
+ * Looks up a cipher suite supported by the TLS stack by its IANA name
+ */
+pub fn cipher_suite(name: &str) -> Option<SupportedCipherSuite> {
+    ring::ALL_CIPHER_SUITES
+        .iter()
+        .find(|suite| suite.suite().as_str() == Some(name))
+        .copied()
+}
+
+pub fn suite_version(suite: SupportedCipherSuite) -> TlsVersion {
+    match suite {
+        SupportedCipherSuite::Tls12(_) => TlsVersion::Tls12,
+        SupportedCipherSuite::Tls13(_) => TlsVersion::Tls13,
+    }
+}
+
+/*
+ * Builds the acceptor of a TLS listener along with its certificates, which
+ * `spawn_watcher` keeps up to date
+ */
+pub fn acceptor(config: &TlsConfig) -> io::Result<(TlsAcceptor, Arc<Certificates>)> {
+    let mut provider = ring::default_provider();
+    if let Some(ciphers) = &config.ciphers {
+        provider.cipher_suites = ciphers
+            .iter()
+            .filter_map(|cipher| cipher_suite(cipher.get_ref()))
+            .collect();
+    }
+    let provider = Arc::new(provider);
+    let certificates = Arc::new(Certificates::load(
+        &config.certificates,
+        Arc::clone(&provider),
+    )?);
+
+    let versions: Vec<&'static SupportedProtocolVersion> = config
+        .versions
+        .iter()
+        .map(|tls_version| match tls_version {
+            TlsVersion::Tls12 => &version::TLS12,
+            TlsVersion::Tls13 => &version::TLS13,
+        })
+        .collect();
+    let mut server = ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&versions)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::clone(&certificates) as Arc<dyn ResolvesServerCert>);
+    // Only HTTP/1.1 is spoken past the handshake
+    server.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok((TlsAcceptor::from(Arc::new(server)), certificates))
+}
+
+/*
+ * The certificates of one TLS listener, picked by SNI during each handshake
+ * and replaced when their files change
+ */
+#[derive(Debug)]
+pub struct Certificates {
+    entries: Vec<Entry>,
+    provider: Arc<CryptoProvider>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    config: CertificateConfig,
+    key: ArcSwap<CertifiedKey>,
+    /*
+     * Modification times of the certificate and key files when last
+     * loaded, or last tried when that failed
+     */
+    modified: Mutex<[Option<SystemTime>; 2]>,
+}
+
+impl Certificates {
+    fn load(configs: &[CertificateConfig], provider: Arc<CryptoProvider>) -> io::Result<Self> {
+        let entries = configs
+            .iter()
+            .map(|config| {
+                let modified = modified(config);
+                let key = load_key(config, &provider)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Ok(Entry {
+                    config: config.clone(),
+                    key: ArcSwap::from_pointee(key),
+                    modified: Mutex::new(modified),
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Certificates { entries, provider })
+    }
+
+    /*
+     * Reloads every certificate whose files changed. New files that fail
+     * to load leave the old certificate in use.
+     */
+    pub fn reload_changed(&self) {
+        for entry in &self.entries {
+            let now = modified(&entry.config);
+            {
+                let mut last = entry.modified.lock().unwrap();
+                if *last == now {
+                    continue;
+                }
+                *last = now;
+            }
+
+            let cert = entry.config.cert.display();
+            match load_key(&entry.config, &self.provider) {
+                Ok(key) => {
+                    entry.key.store(Arc::new(key));
+                    eprintln!("Reloaded certificate {}", cert);
+                }
+                Err(e) => eprintln!("Keeping previous certificate {}: {}", cert, e),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let entry = client_hello
+            .server_name()
+            .and_then(|name| {
+                self.entries
+                    .iter()
+                    .find(|entry| entry.config.hosts.iter().any(|host| host.matches(name)))
+            })
+            .or(self.entries.first())?;
+        Some(entry.key.load_full())
+    }
+}
+
+/*
+ * Starts the thread that checks the certificate files of every TLS listener
+ * for changes
+ */
+pub fn spawn_watcher(certificates: Vec<Arc<Certificates>>) {
+    if certificates.is_empty() {
+        return;
+    }
+    thread::spawn(move || loop {
+        thread::sleep(WATCH_INTERVAL);
+        for listener in &certificates {
+            listener.reload_changed();
+        }
+    });
+}
+
+fn load_key(config: &CertificateConfig, provider: &CryptoProvider) -> Result<CertifiedKey, String> {
+    let failed = |path: &Path, e: &dyn std::fmt::Display| format!("{}: {}", path.display(), e);
+    let chain = CertificateDer::pem_file_iter(&config.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| failed(&config.cert, &e))?;
+    if chain.is_empty() {
+        return Err(failed(&config.cert, &"no certificates found"));
+    }
+    let key = PrivateKeyDer::from_pem_file(&config.key).map_err(|e| failed(&config.key, &e))?;
+    CertifiedKey::from_der(chain, key, provider).map_err(|e| failed(&config.key, &e))
+}
+
+fn modified(config: &CertificateConfig) -> [Option<SystemTime>; 2] {
+    [&config.cert, &config.key].map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+}
+
+/*
+ * Builds the client side of upstream TLS for a pool
+ */
+pub fn client_config(config: &UpstreamTlsConfig) -> Result<ClientConfig, String> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+
+    let builder = match &config.ca_bundle {
+        _ if config.insecure_skip_verify => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(SkipVerification(Arc::clone(&provider)))),
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            let certs = CertificateDer::pem_file_iter(path)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            let (added, _) = roots.add_parsable_certificates(certs);
+            if added == 0 {
+                return Err(format!("{}: no usable CA certificates", path.display()));
+            }
+            builder.with_root_certificates(roots)
+        }
+        None => return Err("no ca_bundle given".to_string()),
+    };
+
+    let mut client = match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => {
+            let client_cert = CertificateConfig {
+                cert: cert.clone(),
+                key: key.clone(),
+                hosts: Vec::new(),
+            };
+            let key = load_key(&client_cert, &provider)?;
+            builder.with_client_cert_resolver(Arc::new(AlwaysResolvesClientCert(Arc::new(key))))
+        }
+        _ => builder.with_no_client_auth(),
+    };
+    client.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(client)
+}
+
+/*
+ * The name a backend's certificate is checked against and sent as SNI: the
+ * pool's `server_name`, or else the host of the backend's address
+ */
+pub fn server_name(config: &UpstreamTlsConfig, address: &str) -> io::Result<ServerName<'static>> {
+    let host = match &config.server_name {
+        Some(name) => name.as_str(),
+        None => {
+            let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+            host.trim_start_matches('[').trim_end_matches(']')
+        }
+    };
+    ServerName::try_from(host.to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/*
+ * Hands the pool's client certificate to every backend that asks for one
+ */
+#[derive(Debug)]
+struct AlwaysResolvesClientCert(Arc<CertifiedKey>);
+
+impl ResolvesClientCert for AlwaysResolvesClientCert {
+    fn resolve(
+        &self,
+        _root_hint_subjects: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.0))
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/*
+ * Accepts whatever certificate a backend presents, while still checking
+ * that the handshake is signed by its key
+ */
+#[derive(Debug)]
+struct SkipVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for SkipVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
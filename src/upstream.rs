@@ -0,0 +1,205 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::{self, TcpStream},
+    time,
+};
+use tokio_rustls::TlsConnector;
+
+use crate::{
+    config::{ConnectionPoolConfig, UpstreamTlsConfig},
+    stream::Stream,
+    tls,
+};
+
+/*
+ * A connection to a backend, fresh or taken from its idle pool
+ */
+#[derive(Debug)]
+pub struct Connection {
+    pub stream: Stream,
+    pub opened: Instant,
+    /*
+     * Whether the connection already carried a request. A reused
+     * connection can turn out to have been closed by the backend in the
+     * meantime, which is not held against the backend.
+     */
+    pub reused: bool,
+}
+
+impl Connection {
+    pub fn new(stream: Stream) -> Self {
+        Connection {
+            stream,
+            opened: Instant::now(),
+            reused: false,
+        }
+    }
+
+    fn expired(&self, config: &ConnectionPoolConfig, now: Instant) -> bool {
+        config
+            .max_lifetime
+            .is_some_and(|lifetime| now.duration_since(self.opened) >= lifetime)
+    }
+}
+
+/*
+ * Keep-alive connections to one backend waiting for their next request.
+ * The most recently used connection is handed out first, so that a quiet
+ * period lets the others run into `idle_timeout` and close.
+ */
+#[derive(Debug, Default)]
+pub struct ConnectionPool {
+    idle: Mutex<Vec<Idle>>,
+}
+
+#[derive(Debug)]
+struct Idle {
+    connection: Connection,
+    since: Instant,
+}
+
+impl ConnectionPool {
+    /*
+     * Takes the freshest idle connection that is still usable, closing any
+     * expired or broken ones found on the way
+     */
+    pub fn checkout(&self, config: &ConnectionPoolConfig) -> Option<Connection> {
+        let now = Instant::now();
+        loop {
+            let Idle { connection, since } = self.idle.lock().unwrap().pop()?;
+            if now.duration_since(since) < config.idle_timeout
+                && !connection.expired(config, now)
+                && is_usable(connection.stream.tcp())
+            {
+                return Some(Connection {
+                    reused: true,
+                    ..connection
+                });
+            }
+        }
+    }
+
+    /*
+     * Puts a connection whose response was read in full back for reuse,
+     * unless the pool is full or the connection has lived long enough
+     */
+    pub fn checkin(&self, connection: Connection, config: &ConnectionPoolConfig) {
+        let now = Instant::now();
+        if connection.expired(config, now) {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < config.max_idle {
+            idle.push(Idle {
+                connection,
+                since: now,
+            });
+        }
+    }
+
+    /*
+     * Closes connections that have been idle or alive for too long
+     */
+    pub fn evict(&self, config: &ConnectionPoolConfig) {
+        let now = Instant::now();
+        let mut idle = self.idle.lock().unwrap();
+        idle.retain(|idle| {
+            now.duration_since(idle.since) < config.idle_timeout
+                && !idle.connection.expired(config, now)
+        });
+        // Shrinks to a lowered `max_idle` after a reload, oldest first
+        let excess = idle.len().saturating_sub(config.max_idle);
+        idle.drain(..excess);
+    }
+
+    pub fn clear(&self) {
+        self.idle.lock().unwrap().clear();
+    }
+
+    pub fn idle_count(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+}
+
+/*
+ * An idle connection must have nothing to read: end of stream means the
+ * backend closed it, and data it was not asked for means the framing of
+ * the last response was off. Over TLS a close_notify or session ticket
+ * counts as data, so such connections are dropped too.
+ */
+fn is_usable(stream: &TcpStream) -> bool {
+    if !matches!(stream.take_error(), Ok(None)) {
+        return false;
+    }
+    matches!(
+        stream.try_read(&mut [0; 1]),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock
+    )
+}
+
+/*
+ * Wraps a fresh backend connection in TLS when its pool asks for it. The
+ * handshake gets as long as the connect did.
+ */
+pub async fn secure(
+    stream: TcpStream,
+    tls: Option<&UpstreamTlsConfig>,
+    address: &str,
+    timeout: Duration,
+) -> io::Result<Stream> {
+    let Some(tls) = tls else {
+        return Ok(Stream::Plain(stream));
+    };
+    let Some(client_config) = &tls.client_config else {
+        return Err(io::Error::other("upstream TLS settings were not loaded"));
+    };
+    let server_name = tls::server_name(tls, address)?;
+    let handshake = TlsConnector::from(Arc::clone(client_config)).connect(server_name, stream);
+    match time::timeout(timeout, handshake).await {
+        Ok(Ok(stream)) => Ok(Stream::Tls(Box::new(stream.into()))),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut)),
+    }
+}
+
+/*
+ * Connects to a backend, introducing the client with a PROXY protocol
+ * header first when given one
+ */
+pub async fn open(
+    address: &str,
+    timeout: Duration,
+    proxy_header: Option<&[u8]>,
+) -> io::Result<TcpStream> {
+    let mut stream = connect(address, timeout).await?;
+    if let Some(header) = proxy_header {
+        stream.write_all(header).await?;
+    }
+    Ok(stream)
+}
+
+/*
+ * Connects to the first resolved address that accepts within `timeout`
+ */
+pub async fn connect(address: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in net::lookup_host(address).await? {
+        match time::timeout(timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => last_error = Some(e),
+            Err(_) => last_error = Some(io::Error::from(io::ErrorKind::TimedOut)),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} did not resolve to any address", address),
+        )
+    }))
+}